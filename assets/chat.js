@@ -3,6 +3,9 @@ let messageCache = new Map();
 let ws = null;
 let reconnectAttempts = 0;
 let selectedMessageId = null;
+let pendingAttachments = [];
+let pendingUploads = [];
+let previewPath = null;
 const MAX_RECONNECT_ATTEMPTS = 5;
 const WEBSOCKET_URL = 'ws://localhost:{{WEBSOCKET_PORT}}/';
 
@@ -51,6 +54,9 @@ function connectWebSocket() {
         sendWebSocketMessage({
             type: 'get_messages'
         });
+        loadChanges();
+        loadDryRun();
+        loadMounts();
     };
     
     ws.onclose = () => {
@@ -102,6 +108,25 @@ function handleWebSocketMessage(data) {
             });
             
             renderMessages([...messageCache.values()], false);
+        } else if (data.type === 'dry_run') {
+            document.getElementById('dryRunToggle').checked = data.enabled;
+        } else if (data.type === 'audit') {
+            renderAudit(data.events);
+        } else if (data.type === 'changes') {
+            renderChanges(data);
+        } else if (data.type === 'search_results') {
+            renderSearchResults(data);
+        } else if (data.type === 'undo_result') {
+            if (data.error) {
+                alert(`Undo failed: ${data.error}`);
+            } else {
+                const failed = data.results.filter(r => !r.success);
+                if (failed.length > 0) {
+                    alert(`Some paths could not be restored:\n${failed.map(r => `${r.path}: ${r.error}`).join('\n')}`);
+                }
+            }
+            loadFileTree();
+            loadChanges();
         } else if (data.type === 'message_update') {
             // Handle bulk message updates (e.g., from get_messages)
             if (data.messages) {
@@ -198,8 +223,13 @@ async function sendMessage() {
         sendWebSocketMessage({
             type: 'send_message',
             content: text,
-            fs_commands: fs_commands.length > 0 ? fs_commands : undefined
+            fs_commands: fs_commands.length > 0 ? fs_commands : undefined,
+            attachments: pendingAttachments.length > 0 ? pendingAttachments : undefined,
+            uploads: pendingUploads.length > 0 ? pendingUploads : undefined
         });
+        pendingAttachments = [];
+        pendingUploads = [];
+        renderPendingAttachments();
 
         // Clear input
         messageInput.value = '';
@@ -228,12 +258,34 @@ function extractFsCommands(content) {
             const operation = cmdElement.getElementsByTagName('operation')[0]?.textContent;
             const path = cmdElement.getElementsByTagName('path')[0]?.textContent;
             const content = cmdElement.getElementsByTagName('content')[0]?.textContent;
+            const depth = cmdElement.getElementsByTagName('depth')[0]?.textContent;
+            const pattern = cmdElement.getElementsByTagName('pattern')[0]?.textContent;
+            const include = cmdElement.getElementsByTagName('include')[0]?.textContent;
+            const context = cmdElement.getElementsByTagName('context')[0]?.textContent;
+            const offset = cmdElement.getElementsByTagName('offset')[0]?.textContent;
+            const limit = cmdElement.getElementsByTagName('limit')[0]?.textContent;
+            const unit = cmdElement.getElementsByTagName('unit')[0]?.textContent;
+            const destination = cmdElement.getElementsByTagName('destination')[0]?.textContent;
+            const overwrite = cmdElement.getElementsByTagName('overwrite')[0]?.textContent;
+            const task = cmdElement.getElementsByTagName('task')[0]?.textContent;
+            const permissions = cmdElement.getElementsByTagName('permissions')[0]?.textContent;
             
             if (operation && path) {
                 commands.push({
                     operation,
                     path,
-                    content: content || undefined
+                    content: content || undefined,
+                    depth: depth ? Number(depth) : undefined,
+                    pattern: pattern || undefined,
+                    include: include || undefined,
+                    context: context ? Number(context) : undefined,
+                    offset: offset ? Number(offset) : undefined,
+                    limit: limit ? Number(limit) : undefined,
+                    unit: unit || undefined,
+                    destination: destination || undefined,
+                    overwrite: overwrite ? overwrite === 'true' : undefined,
+                    task: task || undefined,
+                    permissions: permissions ? permissions.split(',').map(p => p.trim()).filter(p => p) : undefined
                 });
             }
         }
@@ -263,6 +315,15 @@ function handleMessageClick(event) {
     renderMessages([...messageCache.values()], false);
 }
 
+function undoMessage(messageId) {
+    if (confirm('Restore the files this message changed to their previous contents?')) {
+        sendWebSocketMessage({
+            type: 'undo_message',
+            messageId
+        });
+    }
+}
+
 function copyMessageId(messageId) {
     navigator.clipboard.writeText(messageId)
         .then(() => {
@@ -293,7 +354,19 @@ function formatFsResults(results) {
                 <span class="path">${result.path}</span>
         `;
         
-        if (result.data) {
+        if (result.preview) {
+            content += result.preview.map(file => `
+                <div class="file-change">
+                    <div class="file-change-header">
+                        <span>${escapeHtml(file.path)}</span>
+                        <span class="file-change-status">would be ${file.status} (dry run)</span>
+                    </div>
+                    ${file.diff ? `<pre class="diff">${formatDiff(file.diff)}</pre>` : ''}
+                </div>
+            `).join('');
+        } else if (result.data && result.media_type) {
+            content += `<div class="data">[${escapeHtml(result.media_type)} passed to the model]</div>`;
+        } else if (result.data) {
             content += `<div class="data">${escapeHtml(result.data)}</div>`;
         }
         
@@ -366,6 +439,11 @@ function renderMessages(messages, isTyping = false) {
                         </svg>
                         Copy ID
                     </button>
+                    ${msg.fs_results?.some(r => r.snapshots) ? `
+                        <button class="message-action-button undo-button">
+                            Undo changes
+                        </button>
+                    ` : ''}
                 </div>
             </div>
         `).join('')}
@@ -383,6 +461,14 @@ function renderMessages(messages, isTyping = false) {
     messages_elements.forEach(messageElement => {
         messageElement.addEventListener('click', handleMessageClick);
         
+        const undoButton = messageElement.querySelector('.undo-button');
+        if (undoButton) {
+            undoButton.addEventListener('click', (e) => {
+                e.stopPropagation();
+                undoMessage(messageElement.dataset.id);
+            });
+        }
+
         const copyButton = messageElement.querySelector('.copy-button');
         if (copyButton) {
             copyButton.addEventListener('click', (e) => {
@@ -396,6 +482,288 @@ function renderMessages(messages, isTyping = false) {
     messageArea.scrollTop = messageArea.scrollHeight;
 }
 
+// File tree panel
+async function loadFileTree() {
+    const container = document.querySelector('.file-tree-container');
+    try {
+        const response = await fetch('/api/fs/tree');
+        const data = await response.json();
+        if (!response.ok) {
+            container.innerHTML = `<div class="error">${escapeHtml(data.error || 'Failed to load files')}</div>`;
+            return;
+        }
+        container.innerHTML = renderFileTree(data.tree);
+        if (data.truncated) {
+            container.innerHTML += '<div class="command-meta">Listing truncated</div>';
+        }
+    } catch (error) {
+        console.error('Error loading file tree:', error);
+        container.innerHTML = '<div class="error">Failed to load files</div>';
+    }
+}
+
+function renderFileTree(nodes) {
+    if (!nodes || nodes.length === 0) return '';
+    return `
+        <ul>
+            ${nodes.map(node => `
+                <li>
+                    <div class="file-tree-item ${node.kind}" data-path="${escapeHtml(node.path)}" data-kind="${node.kind}">
+                        ${node.kind === 'dir' ? '📁' : '📄'} ${escapeHtml(node.name)}
+                    </div>
+                    ${renderFileTree(node.children)}
+                </li>
+            `).join('')}
+        </ul>
+    `;
+}
+
+async function previewFile(path) {
+    const preview = document.querySelector('.file-preview');
+    const attachButton = document.querySelector('.attach-preview-button');
+    try {
+        const response = await fetch(`/api/fs/read?path=${encodeURIComponent(path)}`);
+        const data = await response.json();
+        if (!response.ok) {
+            preview.textContent = data.error || 'Failed to read file';
+        } else if (data.media_type) {
+            preview.textContent = `[${data.media_type} file]`;
+        } else {
+            preview.textContent = data.content;
+        }
+        preview.hidden = false;
+        previewPath = path;
+        attachButton.hidden = !response.ok;
+    } catch (error) {
+        console.error('Error reading file:', error);
+    }
+}
+
+function handleFileTreeClick(event) {
+    const item = event.target.closest('.file-tree-item');
+    if (!item) return;
+
+    if (item.dataset.kind === 'file') {
+        previewFile(item.dataset.path);
+    } else {
+        // Toggle the directory's children
+        const children = item.nextElementSibling;
+        if (children) {
+            children.hidden = !children.hidden;
+        }
+    }
+}
+
+// Changes panel
+// Mounts panel
+async function loadMounts() {
+    const container = document.querySelector('.mounts-container');
+    try {
+        const response = await fetch('/api/mounts');
+        const data = await response.json();
+        container.innerHTML = data.mounts.map(mount => `
+            <div class="file-change">
+                <div class="file-change-header">
+                    <span>${escapeHtml(mount.name)}: ${escapeHtml(mount.path)}</span>
+                    <span class="mount-status ${mount.status}">${mount.status}${mount.restarts > 0 ? ` (${mount.restarts} restarts)` : ''}</span>
+                </div>
+                ${mount.available ? '' : `<button class="refresh-button" onclick="restartMount('${escapeHtml(mount.name)}')">Restart</button>`}
+            </div>
+        `).join('');
+    } catch (error) {
+        console.error('Error loading mounts:', error);
+        container.innerHTML = '<div class="error">Failed to load mounts</div>';
+    }
+}
+
+async function restartMount(name) {
+    const response = await fetch(`/api/mounts/${encodeURIComponent(name)}/restart`, { method: 'POST' });
+    if (!response.ok) {
+        const data = await response.json();
+        alert(`Restart failed: ${data.error}`);
+    }
+    loadMounts();
+}
+
+// Audit log panel
+function loadAudit() {
+    sendWebSocketMessage({
+        type: 'get_audit',
+        category: document.getElementById('auditCategory').value || undefined,
+        limit: 100
+    });
+}
+
+function renderAudit(events) {
+    const container = document.querySelector('.audit-container');
+    if (events.length === 0) {
+        container.innerHTML = '<div class="command-meta">No events</div>';
+        return;
+    }
+    container.innerHTML = events.slice().reverse().map(event => `
+        <div class="file-change">
+            <div class="file-change-header">
+                <span>${escapeHtml(event.summary)}</span>
+                <span class="file-change-status">${event.category} • ${event.hash.slice(0, 8)}</span>
+            </div>
+        </div>
+    `).join('');
+}
+
+// Message search
+function searchMessages(event) {
+    const q = event.target.value.trim();
+    if (q) {
+        sendWebSocketMessage({ type: 'search', q });
+    } else {
+        document.querySelector('.search-container').innerHTML = '';
+    }
+}
+
+function renderSearchResults(data) {
+    const container = document.querySelector('.search-container');
+    if (data.error) {
+        container.innerHTML = `<div class="error">${escapeHtml(data.error)}</div>`;
+        return;
+    }
+    if (data.results.length === 0) {
+        container.innerHTML = '<div class="command-meta">No matches</div>';
+        return;
+    }
+    container.innerHTML = data.results.map(result => `
+        <div class="file-change">
+            <div class="file-change-header">
+                <span>${escapeHtml(result.snippet || '')}</span>
+                <span class="file-change-status">${result.role} • ${result.message_id.slice(0, 8)}${result.conversation_id ? ` • ${escapeHtml(result.conversation_title || result.conversation_id)}` : ''}</span>
+            </div>
+        </div>
+    `).join('');
+}
+
+function loadChanges() {
+    sendWebSocketMessage({
+        type: 'get_changes'
+    });
+}
+
+function renderChanges(data) {
+    const container = document.querySelector('.changes-container');
+    if (data.error) {
+        container.innerHTML = `<div class="error">${escapeHtml(data.error)}</div>`;
+        return;
+    }
+    if (data.changes.length === 0) {
+        container.innerHTML = '<div class="command-meta">No changes in this conversation</div>';
+        return;
+    }
+
+    container.innerHTML = data.changes.map(change => `
+        <div class="file-change">
+            <div class="file-change-header">
+                <span>${escapeHtml(change.path)}</span>
+                <span class="file-change-status">${change.status} • ${change.operations.length} op(s)</span>
+            </div>
+            ${change.diff ? `<pre class="diff">${formatDiff(change.diff)}</pre>` : ''}
+        </div>
+    `).join('');
+}
+
+function formatDiff(diff) {
+    return diff.split('\n').map(line => {
+        const escaped = escapeHtml(line);
+        if (line.startsWith('+') && !line.startsWith('+++')) return `<span class="added">${escaped}</span>`;
+        if (line.startsWith('-') && !line.startsWith('---')) return `<span class="removed">${escaped}</span>`;
+        if (line.startsWith('@@')) return `<span class="hunk">${escaped}</span>`;
+        return escaped;
+    }).join('\n');
+}
+
+// Dry-run toggle
+async function loadDryRun() {
+    try {
+        const response = await fetch('/api/dry-run');
+        const data = await response.json();
+        document.getElementById('dryRunToggle').checked = data.enabled;
+    } catch (error) {
+        console.error('Error loading dry-run setting:', error);
+    }
+}
+
+function toggleDryRun(event) {
+    sendWebSocketMessage({
+        type: 'set_dry_run',
+        enabled: event.target.checked
+    });
+}
+
+// Import
+async function importConversation(event) {
+    const file = event.target.files[0];
+    event.target.value = '';
+    if (!file) return;
+
+    const response = await fetch('/api/import', {
+        method: 'POST',
+        headers: { 'Content-Type': 'application/json' },
+        body: await file.text()
+    });
+    const data = await response.json();
+    if (!response.ok) {
+        alert(`Import failed: ${data.error}`);
+        return;
+    }
+    sendWebSocketMessage({ type: 'get_messages' });
+    loadDryRun();
+}
+
+// Attachments
+function attachPreviewedFile() {
+    if (previewPath && !pendingAttachments.includes(previewPath)) {
+        pendingAttachments.push(previewPath);
+        renderPendingAttachments();
+    }
+}
+
+function handleUpload(event) {
+    for (const file of event.target.files) {
+        const reader = new FileReader();
+        reader.onload = () => {
+            // Strip the data URL prefix, leaving the base64 payload
+            const data = reader.result.split(',')[1] || '';
+            pendingUploads.push({ name: file.name, data });
+            renderPendingAttachments();
+        };
+        reader.readAsDataURL(file);
+    }
+    event.target.value = '';
+}
+
+function renderPendingAttachments() {
+    const container = document.getElementById('pendingAttachments');
+    const chips = [
+        ...pendingAttachments.map((path, index) => ({ label: path, kind: 'file', index })),
+        ...pendingUploads.map((upload, index) => ({ label: upload.name, kind: 'upload', index }))
+    ];
+    container.innerHTML = chips.map(chip => `
+        <span class="attachment-chip" data-kind="${chip.kind}" data-index="${chip.index}" title="Click to remove">
+            ${chip.kind === 'file' ? '📄' : '⬆'} ${escapeHtml(chip.label)} ✕
+        </span>
+    `).join('');
+}
+
+function handleAttachmentChipClick(event) {
+    const chip = event.target.closest('.attachment-chip');
+    if (!chip) return;
+
+    const index = Number(chip.dataset.index);
+    if (chip.dataset.kind === 'file') {
+        pendingAttachments.splice(index, 1);
+    } else {
+        pendingUploads.splice(index, 1);
+    }
+    renderPendingAttachments();
+}
+
 // Utility functions
 function escapeHtml(unsafe) {
     return unsafe
@@ -534,6 +902,14 @@ function formatRetryTime(timestamp) {
 document.addEventListener('DOMContentLoaded', () => {
     connectWebSocket();
     setupInfoPane();
+    loadFileTree();
+    document.querySelector('.file-tree-container').addEventListener('click', handleFileTreeClick);
+    document.querySelector('.attach-preview-button').addEventListener('click', attachPreviewedFile);
+    document.getElementById('uploadInput').addEventListener('change', handleUpload);
+    document.getElementById('dryRunToggle').addEventListener('change', toggleDryRun);
+    document.getElementById('importInput').addEventListener('change', importConversation);
+    document.getElementById('searchInput').addEventListener('change', searchMessages);
+    document.getElementById('pendingAttachments').addEventListener('click', handleAttachmentChipClick);
 
     // Make sure Available Commands section starts expanded
     const commandsSection = document.querySelector('.info-section.result-block');
@@ -896,6 +896,94 @@ pub mod ntwk {
                 }
             }
         }
+        #[allow(dead_code, clippy::all)]
+        pub mod supervisor_host {
+            #[used]
+            #[doc(hidden)]
+            static __FORCE_SECTION_REF: fn() = super::super::super::__link_custom_section_describing_imports;
+            use super::super::super::_rt;
+            pub type Json = super::super::super::ntwk::theater::types::Json;
+            #[allow(unused_unsafe, clippy::all)]
+            pub fn spawn(manifest_path: &str) {
+                unsafe {
+                    let vec0 = manifest_path;
+                    let ptr0 = vec0.as_ptr().cast::<u8>();
+                    let len0 = vec0.len();
+                    #[cfg(target_arch = "wasm32")]
+                    #[link(wasm_import_module = "ntwk:theater/supervisor-host")]
+                    extern "C" {
+                        #[link_name = "spawn"]
+                        fn wit_import(_: *mut u8, _: usize);
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    fn wit_import(_: *mut u8, _: usize) {
+                        unreachable!()
+                    }
+                    wit_import(ptr0.cast_mut(), len0);
+                }
+            }
+            #[allow(unused_unsafe, clippy::all)]
+            pub fn kill(actor_id: &str) {
+                unsafe {
+                    let vec0 = actor_id;
+                    let ptr0 = vec0.as_ptr().cast::<u8>();
+                    let len0 = vec0.len();
+                    #[cfg(target_arch = "wasm32")]
+                    #[link(wasm_import_module = "ntwk:theater/supervisor-host")]
+                    extern "C" {
+                        #[link_name = "kill"]
+                        fn wit_import(_: *mut u8, _: usize);
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    fn wit_import(_: *mut u8, _: usize) {
+                        unreachable!()
+                    }
+                    wit_import(ptr0.cast_mut(), len0);
+                }
+            }
+            #[allow(unused_unsafe, clippy::all)]
+            pub fn send(actor_id: &str, msg: &Json) -> Json {
+                unsafe {
+                    #[repr(align(4))]
+                    struct RetArea([::core::mem::MaybeUninit<u8>; 8]);
+                    let mut ret_area = RetArea([::core::mem::MaybeUninit::uninit(); 8]);
+                    let vec0 = actor_id;
+                    let ptr0 = vec0.as_ptr().cast::<u8>();
+                    let len0 = vec0.len();
+                    let vec1 = msg;
+                    let ptr1 = vec1.as_ptr().cast::<u8>();
+                    let len1 = vec1.len();
+                    let ptr2 = ret_area.0.as_mut_ptr().cast::<u8>();
+                    #[cfg(target_arch = "wasm32")]
+                    #[link(wasm_import_module = "ntwk:theater/supervisor-host")]
+                    extern "C" {
+                        #[link_name = "send"]
+                        fn wit_import(
+                            _: *mut u8,
+                            _: usize,
+                            _: *mut u8,
+                            _: usize,
+                            _: *mut u8,
+                        );
+                    }
+                    #[cfg(not(target_arch = "wasm32"))]
+                    fn wit_import(
+                        _: *mut u8,
+                        _: usize,
+                        _: *mut u8,
+                        _: usize,
+                        _: *mut u8,
+                    ) {
+                        unreachable!()
+                    }
+                    wit_import(ptr0.cast_mut(), len0, ptr1.cast_mut(), len1, ptr2);
+                    let l3 = *ptr2.add(0).cast::<*mut u8>();
+                    let l4 = *ptr2.add(4).cast::<usize>();
+                    let len5 = l4;
+                    _rt::Vec::from_raw_parts(l3.cast(), len5, len5)
+                }
+            }
+        }
     }
 }
 #[rustfmt::skip]
@@ -1784,9 +1872,9 @@ pub(crate) use __export_single_chat_impl as export;
 #[cfg(target_arch = "wasm32")]
 #[link_section = "component-type:wit-bindgen:0.36.0:ntwk:theater:single-chat:encoded world"]
 #[doc(hidden)]
-pub static __WIT_BINDGEN_COMPONENT_TYPE: [u8; 1824] = *b"\
-\0asm\x0d\0\x01\0\0\x19\x16wit-component-encoding\x04\0\x07\x9e\x0d\x01A\x02\x01\
-A\x1b\x01B\x0e\x01p}\x04\0\x04json\x03\0\0\x01p}\x04\0\x05state\x03\0\x02\x01s\x04\
+pub static __WIT_BINDGEN_COMPONENT_TYPE: [u8; 1958] = *b"\
+\0asm\x0d\0\x01\0\0\x19\x16wit-component-encoding\x04\0\x07\xa4\x0e\x01A\x02\x01\
+A\x1d\x01B\x0e\x01p}\x04\0\x04json\x03\0\0\x01p}\x04\0\x05state\x03\0\x02\x01s\x04\
 \0\x08actor-id\x03\0\x04\x01kw\x01r\x03\x0aevent-types\x06parent\x06\x04data\x01\
 \x04\0\x05event\x03\0\x07\x01r\x02\x04hashw\x05event\x08\x04\0\x0ameta-event\x03\
 \0\x09\x01p\x0a\x01r\x01\x06events\x0b\x04\0\x05chain\x03\0\x0c\x03\0\x12ntwk:th\
@@ -1811,25 +1899,28 @@ http-response\x03\0\x07\x03\0\x17ntwk:theater/http-types\x05\x07\x02\x03\0\x04\x
 http-request\x02\x03\0\x04\x0dhttp-response\x01B\x08\x02\x03\x02\x01\x01\x04\0\x04\
 json\x03\0\0\x02\x03\x02\x01\x08\x04\0\x0chttp-request\x03\0\x02\x02\x03\x02\x01\
 \x09\x04\0\x0dhttp-response\x03\0\x04\x01@\x01\x03req\x03\0\x05\x04\0\x09send-ht\
-tp\x01\x06\x03\0\x18ntwk:theater/http-client\x05\x0a\x02\x03\0\0\x05event\x01B\x09\
-\x02\x03\x02\x01\x01\x04\0\x04json\x03\0\0\x02\x03\x02\x01\x0b\x04\0\x05event\x03\
-\0\x02\x01@\x02\x03msg\x01\x05state\x01\0\x01\x04\0\x0bhandle-send\x01\x04\x01o\x02\
-\x01\x01\x01@\x02\x03msg\x01\x05state\x01\0\x05\x04\0\x0ehandle-request\x01\x06\x04\
-\0\"ntwk:theater/message-server-client\x05\x0c\x01B\x0d\x01q\x07\x04text\0\0\x06\
-binary\0\0\x07connect\0\0\x05close\0\0\x04ping\0\0\x04pong\0\0\x05other\x01s\0\x04\
-\0\x0cmessage-type\x03\0\0\x01p}\x01k\x02\x01ks\x01r\x03\x02ty\x01\x04data\x03\x04\
-text\x04\x04\0\x11websocket-message\x03\0\x05\x01p\x06\x01r\x01\x08messages\x07\x04\
-\0\x12websocket-response\x03\0\x08\x01o\x02\x02\x09\x01@\x02\x07message\x06\x05s\
-tate\x02\0\x0a\x04\0\x0ehandle-message\x01\x0b\x04\0\x1dntwk:theater/websocket-s\
-erver\x05\x0d\x01B\x07\x02\x03\x02\x01\x01\x04\0\x04json\x03\0\0\x02\x03\x02\x01\
-\x0b\x04\0\x05event\x03\0\x02\x01k\x01\x01@\x01\x04data\x04\0\x01\x04\0\x04init\x01\
-\x05\x04\0\x12ntwk:theater/actor\x05\x0e\x02\x03\0\0\x05state\x01B\x09\x02\x03\x02\
-\x01\x0f\x04\0\x05state\x03\0\0\x02\x03\x02\x01\x08\x04\0\x0chttp-request\x03\0\x02\
-\x02\x03\x02\x01\x09\x04\0\x0dhttp-response\x03\0\x04\x01o\x02\x05\x01\x01@\x02\x03\
-req\x03\x05state\x01\0\x06\x04\0\x0ehandle-request\x01\x07\x04\0\x18ntwk:theater\
-/http-server\x05\x10\x04\0\x18ntwk:theater/single-chat\x04\0\x0b\x11\x01\0\x0bsi\
-ngle-chat\x03\0\0\0G\x09producers\x01\x0cprocessed-by\x02\x0dwit-component\x070.\
-220.1\x10wit-bindgen-rust\x060.36.0";
+tp\x01\x06\x03\0\x18ntwk:theater/http-client\x05\x0a\x01B\x08\x02\x03\x02\x01\x01\
+\x04\0\x04json\x03\0\0\x01@\x01\x0dmanifest-paths\x01\0\x04\0\x05spawn\x01\x02\x01\
+@\x01\x08actor-ids\x01\0\x04\0\x04kill\x01\x03\x01@\x02\x08actor-ids\x03msg\x01\0\
+\x01\x04\0\x04send\x01\x04\x03\0\x1cntwk:theater/supervisor-host\x05\x0b\x02\x03\
+\0\0\x05event\x01B\x09\x02\x03\x02\x01\x01\x04\0\x04json\x03\0\0\x02\x03\x02\x01\
+\x0c\x04\0\x05event\x03\0\x02\x01@\x02\x03msg\x01\x05state\x01\0\x01\x04\0\x0bha\
+ndle-send\x01\x04\x01o\x02\x01\x01\x01@\x02\x03msg\x01\x05state\x01\0\x05\x04\0\x0e\
+handle-request\x01\x06\x04\0\"ntwk:theater/message-server-client\x05\x0d\x01B\x0d\
+\x01q\x07\x04text\0\0\x06binary\0\0\x07connect\0\0\x05close\0\0\x04ping\0\0\x04p\
+ong\0\0\x05other\x01s\0\x04\0\x0cmessage-type\x03\0\0\x01p}\x01k\x02\x01ks\x01r\x03\
+\x02ty\x01\x04data\x03\x04text\x04\x04\0\x11websocket-message\x03\0\x05\x01p\x06\
+\x01r\x01\x08messages\x07\x04\0\x12websocket-response\x03\0\x08\x01o\x02\x02\x09\
+\x01@\x02\x07message\x06\x05state\x02\0\x0a\x04\0\x0ehandle-message\x01\x0b\x04\0\
+\x1dntwk:theater/websocket-server\x05\x0e\x01B\x07\x02\x03\x02\x01\x01\x04\0\x04\
+json\x03\0\0\x02\x03\x02\x01\x0c\x04\0\x05event\x03\0\x02\x01k\x01\x01@\x01\x04d\
+ata\x04\0\x01\x04\0\x04init\x01\x05\x04\0\x12ntwk:theater/actor\x05\x0f\x02\x03\0\
+\0\x05state\x01B\x09\x02\x03\x02\x01\x10\x04\0\x05state\x03\0\0\x02\x03\x02\x01\x08\
+\x04\0\x0chttp-request\x03\0\x02\x02\x03\x02\x01\x09\x04\0\x0dhttp-response\x03\0\
+\x04\x01o\x02\x05\x01\x01@\x02\x03req\x03\x05state\x01\0\x06\x04\0\x0ehandle-req\
+uest\x01\x07\x04\0\x18ntwk:theater/http-server\x05\x11\x04\0\x18ntwk:theater/sin\
+gle-chat\x04\0\x0b\x11\x01\0\x0bsingle-chat\x03\0\0\0G\x09producers\x01\x0cproce\
+ssed-by\x02\x0dwit-component\x070.220.1\x10wit-bindgen-rust\x060.36.0";
 #[inline(never)]
 #[doc(hidden)]
 pub fn __link_custom_section_describing_imports() {
@@ -1,5 +1,7 @@
 mod bindings;
 
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
 use bindings::exports::ntwk::theater::actor::Guest as ActorGuest;
 use bindings::exports::ntwk::theater::http_server::Guest as HttpGuest;
 use bindings::exports::ntwk::theater::http_server::HttpResponse;
@@ -8,12 +10,87 @@ use bindings::exports::ntwk::theater::websocket_server::Guest as WebSocketGuest;
 use bindings::exports::ntwk::theater::websocket_server::{
     MessageType, WebsocketMessage, WebsocketResponse,
 };
-use bindings::ntwk::theater::filesystem::{read_file, write_file};
-use bindings::ntwk::theater::message_server_host::request;
-use bindings::ntwk::theater::runtime::{log, spawn};
-use bindings::ntwk::theater::types::Json;
+use bindings::ntwk::theater::filesystem::{
+    create_dir, delete_dir, delete_file, list_files, read_file, write_file,
+};
+use bindings::ntwk::theater::message_server_host::{request, send};
+use bindings::ntwk::theater::runtime::{get_chain, log, spawn};
+use bindings::ntwk::theater::supervisor_host::kill;
+use bindings::ntwk::theater::types::{Json, MetaEvent};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use similar::TextDiff;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+
+// Limits for the /api/fs/tree listing
+const FS_TREE_DEFAULT_DEPTH: usize = 3;
+const FS_TREE_MAX_DEPTH: usize = 8;
+const FS_TREE_MAX_ENTRIES: usize = 500;
+
+// Limits for recursive list-files commands
+const LIST_FILES_MAX_DEPTH: usize = 10;
+const LIST_FILES_MAX_ENTRIES: usize = 1000;
+
+// Limits for search-files and grep
+const SEARCH_MAX_DEPTH: usize = 20;
+const SEARCH_MAX_RESULTS: usize = 200;
+const GREP_MAX_CONTEXT: usize = 10;
+
+// Most text returned by a single read-file, larger reads are truncated
+const READ_MAX_BYTES: usize = 100_000;
+
+// Largest image or PDF passed to the model from a read-file result
+const MAX_MEDIA_BYTES: usize = 5_000_000;
+
+// Limits for files attached to a user message
+const MAX_ATTACHMENT_BYTES: usize = 200_000;
+const MAX_ATTACHMENTS_TOTAL_BYTES: usize = 500_000;
+
+// Entries of the actor's own filesystem a builtin mount may not be in
+const BUILTIN_RESERVED_PATHS: [&str; 6] = [
+    "data",
+    "api-key.txt",
+    "index.html",
+    "chat.js",
+    "styles.css",
+    "init.json",
+];
+
+// Limits for the audit log view of the chain
+const AUDIT_DEFAULT_LIMIT: usize = 200;
+const AUDIT_MAX_LIMIT: usize = 1000;
+const AUDIT_SUMMARY_CHARS: usize = 200;
+
+// Limits for full-text search of messages: results returned, characters of
+// context around a match in a snippet, and messages loaded per store request
+// when the index is built
+const MESSAGE_SEARCH_MAX_RESULTS: usize = 50;
+const SNIPPET_CONTEXT_CHARS: usize = 60;
+const SEARCH_INDEX_BATCH: usize = 200;
+
+// Conversation titles and summaries: the cheap model that writes them, how many
+// turns go by between summary updates, and how much of each message it reads
+const SUMMARY_MODEL: &str = "claude-3-5-haiku-20241022";
+const SUMMARY_INTERVAL_TURNS: usize = 5;
+const SUMMARY_MAX_MESSAGE_CHARS: usize = 2000;
+
+// Messages kept in the in-actor cache, least recently used first out
+const MESSAGE_CACHE_CAPACITY: usize = 1000;
+
+// Events other actors can subscribe to
+const CHAT_EVENTS: [&str; 4] = ["message", "turn_completed", "turn_failed", "fs_mutation"];
+
+// Consecutive restarts of an fs-proxy before it is given up on
+const MAX_PROXY_RESTARTS: u32 = 5;
+
+// Limits for agents spawned with spawn-agent: how deep agents may nest, and how
+// many turns an agent gets to finish its task
+const MAX_AGENT_DEPTH: u32 = 2;
+const MAX_AGENT_TURNS: usize = 10;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct WasmEvent {
@@ -22,29 +99,314 @@ struct WasmEvent {
     data: Vec<u8>,
 }
 
+// `fs_path`, `permissions` and `backend` configure a single mount named "root"
+// when no `mounts` are given
 #[derive(Debug, Serialize, Deserialize)]
 struct InitData {
     store_id: String,
+    #[serde(default)]
     fs_path: String,
+    #[serde(default)]
     permissions: Vec<String>,
+    #[serde(default)]
+    backend: Backend,
+    #[serde(default)]
+    mounts: Vec<Mount>,
     websocket_port: u16,
+    // How many spawn-agent levels above this actor, 0 for the top-level chat
+    #[serde(default)]
+    agent_depth: u32,
+}
+
+// A host directory exposed to the chat through its own fs-proxy. Paths in a
+// mount are addressed as `name:relative/path`, paths without a mount name are
+// in the first mount
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct Mount {
+    name: String,
+    path: String,
+    permissions: Vec<String>,
+    #[serde(default)]
+    backend: Backend,
+    #[serde(default)]
+    fs_proxy_id: Option<String>,
+    #[serde(default)]
+    status: ProxyStatus,
+    // Consecutive restarts, reset once the proxy answers a health check
+    #[serde(default)]
+    restarts: u32,
+    // Handler calls left to wait before the next restart
+    #[serde(default)]
+    backoff: u32,
+}
+
+// How a mount reaches the filesystem: through a spawned fs-proxy actor, or
+// in-actor through the `filesystem` host import. Builtin mount paths must lie
+// within the path of the actor's own filesystem handler
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+enum Backend {
+    #[default]
+    FsProxy,
+    Builtin,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum ProxyStatus {
+    #[default]
+    Starting,
+    Running,
+    Restarting,
+    Failed,
+}
+
+impl Mount {
+    // Forget a proxy that is gone. There is no clock in the actor, so the
+    // backoff before respawning it is counted in handler calls, doubling with
+    // each consecutive restart
+    fn terminated(&mut self) {
+        log(&format!(
+            "FS-Proxy of mount '{}' is down: {:?}",
+            self.name, self.fs_proxy_id
+        ));
+        self.fs_proxy_id = None;
+        self.status = ProxyStatus::Restarting;
+        self.backoff = (1 << self.restarts.min(MAX_PROXY_RESTARTS)) - 1;
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct State {
     store_id: String,
-    fs_proxy_id: Option<String>,
-    fs_path: String,
-    permissions: Vec<String>,
+    mounts: Vec<Mount>,
     head: Option<String>,
+    conversation_id: Option<String>,
+    conversations: Vec<Conversation>,
+    // Number of conversations started, for their ids
+    conversations_started: u64,
+    children: HashMap<String, Vec<String>>,
+    // Dry-run setting of the current conversation, carried over to the next one
+    // when there is none yet
+    dry_run: bool,
+    subscribers: Vec<Subscriber>,
+    // The latest record of the audit log of filesystem changes
+    audit_head: Option<AuditHead>,
+    agent_depth: u32,
     websocket_port: u16,
     api_key: String,
 }
 
+// A conversation tracks the first message and the latest head of its message
+// chain. In dry-run mode its commands are previewed instead of applied. The
+// title is written after the first exchange and the summary every few turns
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Conversation {
+    id: String,
+    head: Option<String>,
+    // Conversations from before conversations had their own ids are
+    // identified by their first message
+    #[serde(default)]
+    root: Option<String>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    title: Option<String>,
+    #[serde(default)]
+    summary: Option<String>,
+}
+
+impl Conversation {
+    fn root(&self) -> &str {
+        self.root.as_deref().unwrap_or(&self.id)
+    }
+}
+
+// A chain event as shown in the audit log. `category` is "fs" for filesystem
+// commands, "llm" for model calls, "state" for state transitions and "other"
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AuditEntry {
+    hash: String,
+    parent: Option<String>,
+    event_type: String,
+    category: String,
+    summary: String,
+}
+
+// A filesystem change in the audit log. Each record carries the hash of the
+// one before it, so altering or dropping a record breaks the chain. The actor
+// has no clock: records are ordered by `sequence`, and `chain_event` is the
+// latest Theater chain event when the record was written
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AuditRecord {
+    sequence: u64,
+    message_id: String,
+    role: String,
+    operation: String,
+    path: String,
+    before: Option<String>,
+    after: Option<String>,
+    chain_event: Option<String>,
+    previous_key: Option<String>,
+    previous_hash: Option<String>,
+    hash: String,
+}
+
+impl AuditRecord {
+    // SHA-1 of the record with an empty `hash`
+    fn compute_hash(&self) -> String {
+        let unhashed = AuditRecord {
+            hash: String::new(),
+            ..self.clone()
+        };
+        sha1_hex(&serde_json::to_vec(&unhashed).unwrap())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AuditHead {
+    key: String,
+    hash: String,
+    sequence: u64,
+}
+
+// Inverted index of the words in message content and result paths. Messages
+// are numbered in the order they were indexed, so results can be listed newest
+// first and postings stay small
+#[derive(Debug, Default)]
+struct SearchIndex {
+    // None for messages that were replaced by a later copy
+    messages: Vec<Option<String>>,
+    terms: HashMap<String, Vec<usize>>,
+}
+
+impl SearchIndex {
+    fn add(&mut self, id: &str, msg: &Message) {
+        if self.messages.iter().flatten().any(|m| m == id) {
+            return;
+        }
+        let number = self.messages.len();
+        self.messages.push(Some(id.to_string()));
+
+        let paths = msg.fs_results.iter().flatten().map(|r| r.path.as_str());
+        let mut terms: Vec<String> = std::iter::once(msg.content.as_str())
+            .chain(paths)
+            .flat_map(search_terms)
+            .collect();
+        terms.sort();
+        terms.dedup();
+        for term in terms {
+            self.terms.entry(term).or_default().push(number);
+        }
+    }
+
+    // Ids of the messages containing every term, newest first. The last term
+    // may be a prefix
+    fn lookup(&self, terms: &[String]) -> Vec<String> {
+        let mut matches: Option<HashSet<usize>> = None;
+        for (i, term) in terms.iter().enumerate() {
+            let prefix = i == terms.len() - 1;
+            let postings: HashSet<usize> = self
+                .terms
+                .iter()
+                .filter(|(word, _)| *word == term || (prefix && word.starts_with(term.as_str())))
+                .flat_map(|(_, numbers)| numbers.iter().copied())
+                .collect();
+            matches = Some(match matches {
+                Some(found) => found.intersection(&postings).copied().collect(),
+                None => postings,
+            });
+        }
+        let mut numbers: Vec<usize> = matches.unwrap_or_default().into_iter().collect();
+        numbers.sort_unstable_by(|a, b| b.cmp(a));
+        numbers
+            .into_iter()
+            .filter_map(|number| self.messages[number].clone())
+            .collect()
+    }
+
+    fn remove(&mut self, id: &str) {
+        for message in self.messages.iter_mut() {
+            if message.as_deref() == Some(id) {
+                *message = None;
+            }
+        }
+    }
+}
+
+thread_local! {
+    // The search index is rebuilt from the store the first time the actor
+    // instance searches, then kept up to date as messages are saved. It is
+    // not part of the state, which is serialized on every call
+    static SEARCH_INDEX: RefCell<Option<SearchIndex>> = const { RefCell::new(None) };
+}
+
+// An actor notified of chat events, all of them when `events` is empty
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Subscriber {
+    actor_id: String,
+    events: Vec<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct AnthropicMessage {
     role: String,
-    content: String,
+    content: Vec<ContentBlock>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    Image {
+        source: MediaSource,
+    },
+    Document {
+        source: MediaSource,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        title: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MediaSource {
+    #[serde(rename = "type")]
+    source_type: String,
+    media_type: String,
+    data: String,
+}
+
+impl ContentBlock {
+    // Build an image or document block from a base64 encoded file
+    fn media(media_type: &str, data: &str) -> Self {
+        let source = MediaSource {
+            source_type: "base64".to_string(),
+            media_type: media_type.to_string(),
+            data: data.to_string(),
+        };
+        if media_type == "application/pdf" {
+            ContentBlock::Document {
+                source,
+                title: None,
+            }
+        } else {
+            ContentBlock::Image { source }
+        }
+    }
+
+    // Build a plain text document block, titled with the file's name
+    fn text_document(title: &str, text: &str) -> Self {
+        ContentBlock::Document {
+            source: MediaSource {
+                source_type: "text".to_string(),
+                media_type: "text/plain".to_string(),
+                data: text.to_string(),
+            },
+            title: Some(title.to_string()),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -53,8 +415,63 @@ struct Message {
     content: String,
     parent: Option<String>,
     id: Option<String>,
+    // Set on the first message of a conversation, so conversations that start
+    // with the same words don't share their messages
+    conversation: Option<String>,
     fs_commands: Option<Vec<FsCommand>>,
     fs_results: Option<Vec<FsResult>>,
+    attachments: Option<Vec<Attachment>>,
+    transaction: Option<Transaction>,
+}
+
+// A message with the messages that branch off it, for exports
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct MessageNode {
+    #[serde(flatten)]
+    message: Message,
+    children: Vec<MessageNode>,
+}
+
+// Filesystem commands of a message that are applied all together or not at all.
+// A message asks for a transaction by carrying one in the `Staged` state
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Transaction {
+    status: TransactionStatus,
+    // The message's commands that are in the transaction, all of them when not
+    // set. The others run on their own, before or after it
+    #[serde(default)]
+    commands: Option<Range<usize>>,
+    failed_command: Option<usize>,
+    error: Option<String>,
+    rollback_errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+enum TransactionStatus {
+    Staged,
+    Committed,
+    RolledBack,
+    Rejected,
+}
+
+// The state of a path before a transaction touched it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+enum PreImage {
+    Missing,
+    File(String),
+    Dir(Vec<(String, Option<String>)>),
+}
+
+// A file attached to a user message, either read from the sandbox or uploaded
+// by the client. Attachments that could not be included keep an error instead
+// of content.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Attachment {
+    name: String,
+    source: String,
+    size: usize,
+    content: Option<String>,
+    error: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -64,6 +481,27 @@ struct FsCommand {
     content: Option<String>,
     old_text: Option<String>,
     new_text: Option<String>,
+    // list-files and search-files: how many directory levels to descend, and a
+    // glob filter. For grep `pattern` is a regex and `include` a glob filter on
+    // the files searched, with `context` lines around each match
+    depth: Option<usize>,
+    pattern: Option<String>,
+    include: Option<String>,
+    context: Option<usize>,
+    // read-file: skip `offset` and return at most `limit` units, where `unit`
+    // is "lines" (the default) or "bytes", optionally with line numbers
+    offset: Option<usize>,
+    limit: Option<usize>,
+    unit: Option<String>,
+    line_numbers: Option<bool>,
+    // move-file, copy-file and copy-dir: the target path, replaced only when
+    // `overwrite` is set
+    destination: Option<String>,
+    overwrite: Option<bool>,
+    // spawn-agent: the task to delegate and the permissions granted to the
+    // agent, all of the mount's when not given
+    task: Option<String>,
+    permissions: Option<Vec<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -73,13 +511,185 @@ struct FsResult {
     path: String,
     data: Option<String>,
     error: Option<String>,
+    // Set for image and PDF reads, in which case `data` is base64 encoded
+    media_type: Option<String>,
+    entries: Option<Vec<FsEntry>>,
+    // Store keys of the pre-images of the paths this command changed
+    snapshots: Option<Vec<Snapshot>>,
+    // What the command would have changed, set for commands run in dry-run mode
+    preview: Option<Vec<FilePreview>>,
+    // SHA-1 of the paths this command changed, before and after it ran
+    hashes: Option<Vec<PathHashes>>,
+}
+
+// Content hashes of a path, None when it doesn't exist
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct PathHashes {
+    path: String,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FilePreview {
+    path: String,
+    status: String,
+    diff: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Snapshot {
+    path: String,
+    key: String,
+}
+
+// The net change to one file across a conversation, as a unified diff from its
+// first pre-image to its current content
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FileChange {
+    path: String,
+    status: String,
+    operations: Vec<ChangeOperation>,
+    diff: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ChangeOperation {
+    message_id: Option<String>,
+    operation: String,
+    path: String,
+}
+
+// A directory entry returned by list-files. `path` is relative to the
+// filesystem root. Neither backend reports sizes or modification times, which
+// would take reading every file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct FsEntry {
+    name: String,
+    #[serde(default)]
+    path: String,
+    #[serde(default)]
+    kind: String,
+}
+
+impl FsCommand {
+    fn new(operation: &str, path: &str) -> Self {
+        Self {
+            operation: operation.to_string(),
+            path: path.to_string(),
+            content: None,
+            old_text: None,
+            new_text: None,
+            depth: None,
+            pattern: None,
+            include: None,
+            context: None,
+            offset: None,
+            limit: None,
+            unit: None,
+            line_numbers: None,
+            destination: None,
+            overwrite: None,
+            task: None,
+            permissions: None,
+        }
+    }
+}
+
+impl Attachment {
+    fn from_bytes(name: String, source: &str, bytes: &[u8]) -> Self {
+        let mut attachment = Self {
+            name,
+            source: source.to_string(),
+            size: bytes.len(),
+            content: None,
+            error: None,
+        };
+
+        if bytes.len() > MAX_ATTACHMENT_BYTES {
+            attachment.error = Some(format!(
+                "File is {} bytes, larger than the {} byte attachment limit",
+                bytes.len(),
+                MAX_ATTACHMENT_BYTES
+            ));
+        } else if is_binary(bytes) {
+            attachment.error = Some("Binary files cannot be attached".to_string());
+        } else {
+            attachment.content = Some(String::from_utf8_lossy(bytes).into_owned());
+        }
+        attachment
+    }
+
+    fn failed(name: String, source: &str, error: String) -> Self {
+        Self {
+            name,
+            source: source.to_string(),
+            size: 0,
+            content: None,
+            error: Some(error),
+        }
+    }
+}
+
+impl Transaction {
+    fn staged() -> Self {
+        Self {
+            status: TransactionStatus::Staged,
+            commands: None,
+            failed_command: None,
+            error: None,
+            rollback_errors: Vec::new(),
+        }
+    }
+}
+
+impl FsResult {
+    fn succeeded(operation: String, path: String, data: Option<String>) -> Self {
+        Self {
+            success: true,
+            operation,
+            path,
+            data,
+            error: None,
+            media_type: None,
+            entries: None,
+            snapshots: None,
+            preview: None,
+            hashes: None,
+        }
+    }
+
+    fn failure(operation: String, path: String, error: String) -> Self {
+        Self {
+            success: false,
+            operation,
+            path,
+            data: None,
+            error: Some(error),
+            media_type: None,
+            entries: None,
+            snapshots: None,
+            preview: None,
+            hashes: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FsTreeNode {
+    name: String,
+    path: String,
+    kind: String,
+    children: Option<Vec<FsTreeNode>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
 enum FsResponseData {
     FileList(Vec<String>),
+    Entries(Vec<FsEntry>),
     FileContent(String),
+    FileBytes(Vec<u8>),
     None,
 }
 
@@ -99,10 +709,194 @@ struct Request {
 #[derive(Debug, Serialize, Deserialize)]
 enum Action {
     Get(String),
+    GetMany(Vec<String>),
     Put(Vec<u8>),
+    PutBase64(String),
     All(()),
 }
 
+// A reply of the store actor. Values come as byte arrays, or as base64 strings
+// from stores that send them compactly
+#[derive(Debug, Deserialize)]
+struct StoreResponse {
+    status: String,
+    #[serde(default)]
+    key: Option<String>,
+    #[serde(default)]
+    value: Option<StoreValue>,
+    #[serde(default)]
+    values: Option<Vec<Option<StoreValue>>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StoreValue {
+    Bytes(Vec<u8>),
+    Base64(String),
+}
+
+impl StoreValue {
+    fn into_bytes(self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            StoreValue::Bytes(bytes) => Ok(bytes),
+            StoreValue::Base64(data) => Ok(BASE64.decode(data)?),
+        }
+    }
+}
+
+// Content-addressed storage of values under the keys `put` returns
+trait Store {
+    fn put(&self, bytes: Vec<u8>) -> Result<String, Box<dyn std::error::Error>>;
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+    // The values of several keys, in the order of `keys`
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+}
+
+// The store actor, reached with message-server requests
+struct StoreActor<'a> {
+    id: &'a String,
+}
+
+impl StoreActor<'_> {
+    // Send an action to the store. The outer error is a failed request, which
+    // may be transient; the inner one is a reply the actor doesn't understand
+    fn send(
+        &self,
+        action: Action,
+    ) -> Result<Result<StoreResponse, String>, Box<dyn std::error::Error>> {
+        let req = Request {
+            _type: "request".to_string(),
+            data: action,
+        };
+
+        let request_bytes = serde_json::to_vec(&req)?;
+        let response_bytes = request(self.id, &request_bytes)?;
+        Ok(serde_json::from_slice(&response_bytes).map_err(|e| e.to_string()))
+    }
+}
+
+thread_local! {
+    // Cleared when the store rejects PutBase64 or GetMany, after which values
+    // are sent as byte arrays and fetched one request at a time. A store that
+    // understands them answers with an "ok" status, with null for keys it
+    // doesn't have, so any other reply is taken as a rejection of the action
+    static STORE_PUT_BASE64: Cell<bool> = const { Cell::new(true) };
+    static STORE_GET_MANY: Cell<bool> = const { Cell::new(true) };
+}
+
+impl Store for StoreActor<'_> {
+    fn put(&self, bytes: Vec<u8>) -> Result<String, Box<dyn std::error::Error>> {
+        if STORE_PUT_BASE64.get() {
+            match self.send(Action::PutBase64(BASE64.encode(&bytes))) {
+                Ok(Ok(StoreResponse {
+                    status,
+                    key: Some(key),
+                    ..
+                })) if status == "ok" => return Ok(key),
+                Ok(_) => {
+                    log("Store does not support PutBase64, sending values as byte arrays");
+                    STORE_PUT_BASE64.set(false);
+                }
+                // The request itself failed, try again with a plain Put
+                Err(e) => log(&format!("PutBase64 request failed: {}", e)),
+            }
+        }
+
+        let response = self.send(Action::Put(bytes))??;
+        if response.status == "ok" {
+            response.key.ok_or("No key in response".into())
+        } else {
+            Err("Failed to store value".into())
+        }
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let response = self.send(Action::Get(key.to_string()))??;
+        match response.value {
+            Some(value) if response.status == "ok" => value.into_bytes(),
+            _ => Err(format!("Failed to load {}", key).into()),
+        }
+    }
+
+    fn get_many(&self, keys: &[String]) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+        if keys.len() > 1 && STORE_GET_MANY.get() {
+            match self.send(Action::GetMany(keys.to_vec())) {
+                Ok(Ok(StoreResponse {
+                    status,
+                    values: Some(values),
+                    ..
+                })) if status == "ok" && values.len() == keys.len() => {
+                    return keys
+                        .iter()
+                        .zip(values)
+                        .map(|(key, value)| {
+                            value
+                                .ok_or_else(|| format!("Failed to load {}", key))?
+                                .into_bytes()
+                        })
+                        .collect();
+                }
+                Ok(_) => {
+                    log("Store does not support GetMany, loading values one at a time");
+                    STORE_GET_MANY.set(false);
+                }
+                // The request itself failed, try again one key at a time
+                Err(e) => log(&format!("GetMany request failed: {}", e)),
+            }
+        }
+        keys.iter().map(|key| self.get(key)).collect()
+    }
+}
+
+// Least recently used cache of loaded messages. Messages are content addressed,
+// so a cached message never goes stale
+struct MessageCache {
+    tick: u64,
+    entries: HashMap<String, (u64, Message)>,
+}
+
+impl MessageCache {
+    fn get(&mut self, id: &str) -> Option<Message> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.get_mut(id).map(|(used, message)| {
+            *used = tick;
+            message.clone()
+        })
+    }
+
+    fn insert(&mut self, message: Message) {
+        let Some(id) = message.id.clone() else {
+            return;
+        };
+        if self.entries.len() >= MESSAGE_CACHE_CAPACITY && !self.entries.contains_key(&id) {
+            if let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(id, _)| id.clone())
+            {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.tick += 1;
+        self.entries.insert(id, (self.tick, message));
+    }
+}
+
+thread_local! {
+    // Lives as long as the actor instance rather than in its state, which is
+    // serialized on every call
+    static MESSAGE_CACHE: RefCell<MessageCache> = RefCell::new(MessageCache {
+        tick: 0,
+        entries: HashMap::new(),
+    });
+}
+
 impl Message {
     fn new(role: String, content: String, parent: Option<String>) -> Self {
         Self {
@@ -110,8 +904,11 @@ impl Message {
             content,
             parent,
             id: None,
+            conversation: None,
             fs_commands: None,
             fs_results: None,
+            attachments: None,
+            transaction: None,
         }
     }
 
@@ -177,11 +974,30 @@ fn parse_fs_commands(response: &str) -> Option<Vec<FsCommand>> {
                     let path = &cmd_xml[path_start + 6..path_end];
 
                     commands.push(FsCommand {
-                        operation: operation.to_string(),
-                        path: path.to_string(),
                         content: extract_tag_content(cmd_xml, "content"),
                         old_text: extract_tag_content(cmd_xml, "old_text"),
                         new_text: extract_tag_content(cmd_xml, "new_text"),
+                        depth: extract_tag_content(cmd_xml, "depth").and_then(|d| d.parse().ok()),
+                        pattern: extract_tag_content(cmd_xml, "pattern"),
+                        include: extract_tag_content(cmd_xml, "include"),
+                        context: extract_tag_content(cmd_xml, "context")
+                            .and_then(|c| c.parse().ok()),
+                        offset: extract_tag_content(cmd_xml, "offset").and_then(|o| o.parse().ok()),
+                        limit: extract_tag_content(cmd_xml, "limit").and_then(|l| l.parse().ok()),
+                        unit: extract_tag_content(cmd_xml, "unit"),
+                        line_numbers: extract_tag_content(cmd_xml, "line_numbers")
+                            .and_then(|n| n.parse().ok()),
+                        destination: extract_tag_content(cmd_xml, "destination"),
+                        overwrite: extract_tag_content(cmd_xml, "overwrite")
+                            .and_then(|o| o.parse().ok()),
+                        task: extract_tag_content(cmd_xml, "task"),
+                        permissions: extract_tag_content(cmd_xml, "permissions").map(|p| {
+                            p.split(',')
+                                .map(|p| p.trim().to_string())
+                                .filter(|p| !p.is_empty())
+                                .collect()
+                        }),
+                        ..FsCommand::new(operation, path)
                     });
                 }
             }
@@ -195,6 +1011,19 @@ fn parse_fs_commands(response: &str) -> Option<Vec<FsCommand>> {
     }
 }
 
+// Helper function to find the commands of a response inside its
+// <fs-transaction> block, as indices into all the commands of the response
+fn transaction_commands(response: &str) -> Option<Range<usize>> {
+    let start = response.find("<fs-transaction>")?;
+    let end = response[start..]
+        .find("</fs-transaction>")
+        .map_or(response.len(), |end| start + end);
+    let count = |text: &str| parse_fs_commands(text).map_or(0, |commands| commands.len());
+    let first = count(&response[..start]);
+    let commands = first..first + count(&response[start..end]);
+    (!commands.is_empty()).then_some(commands)
+}
+
 fn extract_tag_content(xml: &str, tag: &str) -> Option<String> {
     if let (Some(start), Some(end)) = (
         xml.find(&format!("<{}>", tag)),
@@ -210,14 +1039,19 @@ fn extract_tag_content(xml: &str, tag: &str) -> Option<String> {
 impl State {
     fn process_message(&mut self, message_state: &mut MessageState) -> Result<(), String> {
         // Step 1: Process any filesystem commands
-        if let Some(commands) = &message_state.message.fs_commands {
+        if message_state.message.fs_commands.is_some() {
             message_state.status = MessageStatus::ProcessingCommands;
-            message_state.message.fs_results = Some(self.process_fs_commands(commands.clone()));
+            self.run_fs_commands(&mut message_state.message);
 
             // Update message with results
             if let Ok(updated_id) = self.save_message(&message_state.message) {
                 message_state.message.id = Some(updated_id.clone());
-                self.head = Some(updated_id);
+                self.record_audit(
+                    &updated_id,
+                    &message_state.message.role,
+                    message_state.message.fs_results.iter().flatten(),
+                );
+                self.set_head(updated_id);
             }
         }
 
@@ -247,16 +1081,31 @@ impl State {
 
                     // Parse and process any filesystem commands in the response
                     if let Some(fs_commands) = parse_fs_commands(&ai_response) {
-                        ai_msg.fs_commands = Some(fs_commands.clone());
-                        ai_msg.fs_results = Some(self.process_fs_commands(fs_commands));
+                        ai_msg.fs_commands = Some(fs_commands);
+                        ai_msg.transaction =
+                            transaction_commands(&ai_response).map(|commands| Transaction {
+                                commands: Some(commands),
+                                ..Transaction::staged()
+                            });
+                        self.run_fs_commands(&mut ai_msg);
                     }
 
                     // Save AI message
                     if let Ok(ai_msg_id) = self.save_message(&ai_msg) {
-                        self.head = Some(ai_msg_id.clone());
+                        self.record_audit(
+                            &ai_msg_id,
+                            &ai_msg.role,
+                            ai_msg.fs_results.iter().flatten(),
+                        );
+                        self.set_head(ai_msg_id.clone());
                         ai_msg.id = Some(ai_msg_id);
+                        self.describe_conversation();
 
                         message_state.status = MessageStatus::Completed;
+                        self.notify(
+                            "turn_completed",
+                            json!({ "message_id": message_state.message.id, "reply": ai_msg }),
+                        );
                         return Ok(());
                     } else {
                         let error = "Failed to save AI message".to_string();
@@ -274,31 +1123,229 @@ impl State {
             }
         }
 
+        // An assistant message is done once its commands have run
+        message_state.status = MessageStatus::Completed;
         Ok(())
     }
 
-    fn handle_retry(&self, message_state: &mut MessageState, error: &str) {
-        message_state.last_error = Some(error.to_string());
+    fn send_user_message(
+        &mut self,
+        content: String,
+        fs_commands: Option<Vec<FsCommand>>,
+        attachments: Option<Vec<Attachment>>,
+        transaction: bool,
+    ) -> Result<MessageState, String> {
+        self.health_check();
 
-        if message_state.retries < 3
-            && (error.contains("529") || // Cloudflare overloaded
-            error.contains("rate_limit") ||
-            error.contains("overloaded"))
-        {
-            message_state.status = MessageStatus::Pending;
-            message_state.retries += 1;
-        } else {
-            message_state.status = MessageStatus::Failed;
+        // Create initial message state
+        let mut message_state = MessageState {
+            message: Message::new("user".to_string(), content, self.head.clone()),
+            status: MessageStatus::Pending,
+            retries: 0,
+            last_error: None,
+        };
+        message_state.message.fs_commands = fs_commands;
+        message_state.message.attachments = attachments;
+        if transaction && message_state.message.fs_commands.is_some() {
+            message_state.message.transaction = Some(Transaction::staged());
+        }
+        if self.head.is_none() {
+            let conversation_id = match &self.conversation_id {
+                Some(id) => id.clone(),
+                None => self.start_conversation(),
+            };
+            message_state.message.conversation = Some(conversation_id);
         }
-    }
 
-    fn should_retry(&self, message_state: &MessageState, error: &str) -> bool {
-        // Only retry if we haven't hit the limit and it's a retryable error
-        message_state.retries < 3
-            && (error.contains("529") || // Cloudflare overloaded
-            error.contains("rate_limit") ||
-            error.contains("overloaded"))
-    }
+        // Save initial message and process
+        let msg_id = self
+            .save_message(&message_state.message)
+            .map_err(|e| format!("Failed to save message: {}", e))?;
+        message_state.message.id = Some(msg_id.clone());
+        self.set_head(msg_id);
+
+        if let Err(error) = self.process_message(&mut message_state) {
+            self.handle_retry(&mut message_state, &error);
+        }
+        Ok(message_state)
+    }
+
+    fn retry_message(&mut self, message_id: &str) -> Result<MessageState, String> {
+        self.health_check();
+        let message = self
+            .load_message(message_id)
+            .map_err(|e| format!("Failed to load message {}: {}", message_id, e))?;
+        let mut message_state = MessageState {
+            message,
+            status: MessageStatus::Pending,
+            retries: 0,
+            last_error: None,
+        };
+
+        if let Err(error) = self.process_message(&mut message_state) {
+            self.handle_retry(&mut message_state, &error);
+        }
+        Ok(message_state)
+    }
+
+    fn set_head(&mut self, id: String) {
+        let conversation_id = match &self.conversation_id {
+            Some(conversation_id) => conversation_id.clone(),
+            None => self.start_conversation(),
+        };
+        if let Some(conversation) = self
+            .conversations
+            .iter_mut()
+            .find(|c| c.id == conversation_id)
+        {
+            // First message of a new conversation
+            if conversation.head.is_none() {
+                conversation.root = Some(id.clone());
+            }
+            conversation.head = Some(id.clone());
+        }
+        self.head = Some(id);
+    }
+
+    fn next_conversation_id(&self) -> String {
+        format!("conversation-{}", self.conversations_started + 1)
+    }
+
+    // Make a new, empty conversation current, returning its id
+    fn start_conversation(&mut self) -> String {
+        let id = self.next_conversation_id();
+        self.conversations_started += 1;
+        self.conversations.push(Conversation {
+            id: id.clone(),
+            head: None,
+            root: None,
+            dry_run: self.dry_run,
+            title: None,
+            summary: None,
+        });
+        self.conversation_id = Some(id.clone());
+        self.head = None;
+        id
+    }
+
+    fn set_dry_run(&mut self, enabled: bool) {
+        self.dry_run = enabled;
+        if let Some(conversation) = self
+            .conversations
+            .iter_mut()
+            .find(|c| Some(&c.id) == self.conversation_id.as_ref())
+        {
+            conversation.dry_run = enabled;
+        }
+    }
+
+    // Make another conversation current, picking up its head and dry-run setting
+    fn switch_conversation(&mut self, id: &str) -> Result<(), String> {
+        let conversation = self
+            .conversations
+            .iter()
+            .find(|c| c.id == id)
+            .ok_or_else(|| format!("Conversation {} not found", id))?;
+        self.head = conversation.head.clone();
+        self.dry_run = conversation.dry_run;
+        self.conversation_id = Some(conversation.id.clone());
+        Ok(())
+    }
+
+    // The next message starts a new conversation
+    fn new_conversation(&mut self) {
+        self.conversation_id = None;
+        self.head = None;
+    }
+
+    fn delete_conversation(&mut self, id: &str) -> bool {
+        let before = self.conversations.len();
+        self.conversations.retain(|c| c.id != id);
+        if self.conversations.len() == before {
+            return false;
+        }
+
+        if self.conversation_id.as_deref() == Some(id) {
+            self.conversation_id = None;
+            self.head = None;
+        }
+        true
+    }
+
+    fn handle_retry(&self, message_state: &mut MessageState, error: &str) {
+        message_state.last_error = Some(error.to_string());
+
+        if message_state.retries < 3
+            && (error.contains("529") || // Cloudflare overloaded
+            error.contains("rate_limit") ||
+            error.contains("overloaded"))
+        {
+            message_state.status = MessageStatus::Pending;
+            message_state.retries += 1;
+        } else {
+            message_state.status = MessageStatus::Failed;
+            self.notify(
+                "turn_failed",
+                json!({ "message_id": message_state.message.id, "error": error }),
+            );
+        }
+    }
+
+    fn subscribe(&mut self, actor_id: &str, events: Vec<String>) -> Result<(), String> {
+        if let Some(event) = events.iter().find(|e| !CHAT_EVENTS.contains(&e.as_str())) {
+            return Err(format!(
+                "Unknown event '{}', events: {:?}",
+                event, CHAT_EVENTS
+            ));
+        }
+        self.unsubscribe(actor_id);
+        self.subscribers.push(Subscriber {
+            actor_id: actor_id.to_string(),
+            events,
+        });
+        Ok(())
+    }
+
+    fn unsubscribe(&mut self, actor_id: &str) -> bool {
+        let before = self.subscribers.len();
+        self.subscribers.retain(|s| s.actor_id != actor_id);
+        self.subscribers.len() != before
+    }
+
+    // Send an event to the actors subscribed to it. Notifications are fire and
+    // forget, an actor that can't be reached stays subscribed
+    fn notify(&self, event: &str, payload: Value) {
+        let mut notification = json!({
+            "type": "chat_event",
+            "event": event,
+            "conversation_id": self.conversation_id,
+        });
+        if let (Some(notification), Value::Object(payload)) =
+            (notification.as_object_mut(), payload)
+        {
+            notification.extend(payload);
+        }
+        let bytes = serde_json::to_vec(&notification).unwrap();
+
+        for subscriber in &self.subscribers {
+            if subscriber.events.is_empty() || subscriber.events.iter().any(|e| e == event) {
+                if let Err(e) = send(&subscriber.actor_id, &bytes) {
+                    log(&format!(
+                        "Failed to notify {} of {}: {}",
+                        subscriber.actor_id, event, e
+                    ));
+                }
+            }
+        }
+    }
+
+    fn should_retry(&self, message_state: &MessageState, error: &str) -> bool {
+        // Only retry if we haven't hit the limit and it's a retryable error
+        message_state.retries < 3
+            && (error.contains("529") || // Cloudflare overloaded
+            error.contains("rate_limit") ||
+            error.contains("overloaded"))
+    }
 
     fn handle_error(&self, message_state: &mut MessageState, error: String) {
         message_state.last_error = Some(error.clone());
@@ -319,81 +1366,559 @@ impl State {
         }
     }
 
-    fn resolve_path(&self, relative_path: &str) -> String {
-        if relative_path.starts_with("/") {
-            // Don't modify absolute paths
-            relative_path.to_string()
-        } else {
-            // Join the base path with the relative path
-            let base_path = &self.fs_path;
-            if relative_path == "." {
-                base_path.to_string()
-            } else {
-                format!("{}/{}", base_path, relative_path)
+    // Split a `mount:relative/path` into its mount and the path within it.
+    // Permissions are per mount, so the path has to stay inside it: absolute
+    // paths and `..` are refused whatever the backend
+    fn mount_for<'a>(&self, path: &'a str) -> Result<(&Mount, &'a str), String> {
+        let (mount, relative) = match path.split_once(':') {
+            Some((name, relative)) if !name.contains('/') => {
+                match self.mounts.iter().find(|m| m.name == name) {
+                    Some(mount) => (mount, relative),
+                    None => {
+                        return Err(format!(
+                            "Unknown mount '{}', mounts: {:?}",
+                            name,
+                            self.mounts.iter().map(|m| &m.name).collect::<Vec<_>>()
+                        ))
+                    }
+                }
             }
+            _ => (self.mounts.first().ok_or("No filesystem mounted")?, path),
+        };
+        if relative.starts_with('/') || relative.split('/').any(|c| c == "..") {
+            return Err(format!(
+                "Path {} is outside of mount '{}'",
+                path, mount.name
+            ));
         }
+        Ok((mount, relative))
     }
 
-    fn save_message(&self, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
-        let req = Request {
-            _type: "request".to_string(),
-            data: Action::Put(serde_json::to_vec(&msg)?),
-        };
+    // Respawn the fs-proxies that are down once their backoff has passed
+    fn supervise(&mut self) {
+        for mount in self.mounts.iter_mut() {
+            if mount.status != ProxyStatus::Restarting {
+                continue;
+            }
+            if mount.backoff > 0 {
+                mount.backoff -= 1;
+                continue;
+            }
+            if mount.restarts >= MAX_PROXY_RESTARTS {
+                log(&format!(
+                    "Giving up on the fs-proxy of mount '{}' after {} restarts",
+                    mount.name, mount.restarts
+                ));
+                mount.status = ProxyStatus::Failed;
+                continue;
+            }
 
-        let request_bytes = serde_json::to_vec(&req)?;
-        let response_bytes = request(&self.store_id, &request_bytes)?;
+            mount.restarts += 1;
+            match spawn_fs_proxy(mount) {
+                Ok(fs_proxy_id) => {
+                    log(&format!(
+                        "Respawned fs-proxy of mount '{}': {}",
+                        mount.name, fs_proxy_id
+                    ));
+                    mount.fs_proxy_id = Some(fs_proxy_id);
+                    mount.status = ProxyStatus::Starting;
+                }
+                Err(e) => {
+                    log(&e);
+                    mount.terminated();
+                }
+            }
+        }
+    }
 
-        let response: Value = serde_json::from_slice(&response_bytes)?;
-        if response["status"].as_str() == Some("ok") {
-            response["key"]
-                .as_str()
-                .map(|s| s.to_string())
-                .ok_or("No key in response".into())
+    // Ping every fs-proxy with a listing of its root. A proxy that can't be
+    // reached may still be running but hung, so it is killed before it is
+    // replaced
+    fn health_check(&mut self) {
+        let alive: Vec<Option<bool>> = self
+            .mounts
+            .iter()
+            .map(|mount| {
+                mount.fs_proxy_id.as_ref().map(|_| {
+                    self.fs_request("list-files", &format!("{}:", mount.name), None)
+                        .is_ok()
+                })
+            })
+            .collect();
+        for (mount, alive) in self.mounts.iter_mut().zip(alive) {
+            match alive {
+                Some(true) => {
+                    mount.status = ProxyStatus::Running;
+                    mount.restarts = 0;
+                }
+                Some(false) => {
+                    if let Some(fs_proxy_id) = &mount.fs_proxy_id {
+                        kill(fs_proxy_id);
+                    }
+                    mount.terminated();
+                }
+                None => {}
+            }
+        }
+        self.supervise();
+    }
+
+    // Restart a proxy by hand, e.g. after it was given up on
+    fn restart_mount(&mut self, name: &str) -> Result<(), String> {
+        let mount = self
+            .mounts
+            .iter_mut()
+            .find(|m| m.name == name)
+            .ok_or_else(|| format!("Unknown mount '{}'", name))?;
+        if mount.backend == Backend::Builtin {
+            return Err(format!("Mount '{}' has no fs-proxy", name));
+        }
+        if mount.fs_proxy_id.is_some() {
+            return Err(format!("The fs-proxy of mount '{}' is running", name));
+        }
+        mount.status = ProxyStatus::Restarting;
+        mount.restarts = 0;
+        mount.backoff = 0;
+        self.supervise();
+        Ok(())
+    }
+
+    // Join the base path of a mount with a path inside it, as checked by
+    // `mount_for`
+    fn resolve_path(&self, mount: &Mount, relative_path: &str) -> String {
+        let base_path = &mount.path;
+        if relative_path == "." || relative_path.is_empty() {
+            base_path.to_string()
         } else {
-            Err("Failed to save message".into())
+            format!("{}/{}", base_path, relative_path)
         }
     }
 
-    fn load_message(&self, id: &str) -> Result<Message, Box<dyn std::error::Error>> {
-        let req = Request {
-            _type: "request".to_string(),
-            data: Action::Get(id.to_string()),
-        };
+    fn store(&self) -> StoreActor<'_> {
+        StoreActor { id: &self.store_id }
+    }
 
-        let request_bytes = serde_json::to_vec(&req)?;
-        let response_bytes = request(&self.store_id, &request_bytes)?;
-
-        let response: Value = serde_json::from_slice(&response_bytes)?;
-        if response["status"].as_str() == Some("ok") {
-            if let Some(value) = response.get("value") {
-                let bytes = value
-                    .as_array()
-                    .ok_or("Expected byte array")?
+    // Store a value in the store actor, returning its key
+    fn put_blob(&self, bytes: Vec<u8>) -> Result<String, Box<dyn std::error::Error>> {
+        self.store().put(bytes)
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.store().get(key)
+    }
+
+    fn save_message(&mut self, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
+        let key = self.store_message(msg)?;
+        self.register_message(&key, msg);
+        Ok(key)
+    }
+
+    // Put a message in the store without linking it into the indexes
+    fn store_message(&self, msg: &Message) -> Result<String, Box<dyn std::error::Error>> {
+        let key = self
+            .put_blob(encode_message(msg)?)
+            .map_err(|e| format!("Failed to save message: {}", e))?;
+        MESSAGE_CACHE.with_borrow_mut(|cache| cache.insert(msg.clone().with_id(key.clone())));
+        Ok(key)
+    }
+
+    // Link a stored message into the children and search indexes and tell
+    // subscribers about it
+    fn register_message(&mut self, key: &str, msg: &Message) {
+        let key = key.to_string();
+
+        // A message saved again, e.g. with the results of its commands,
+        // replaces its earlier copy unless that copy already has replies
+        let replaced = msg
+            .id
+            .clone()
+            .filter(|old| *old != key && !self.children.contains_key(old));
+
+        // Index the message under its parent so children can be looked up
+        if let Some(parent) = &msg.parent {
+            let children = self.children.entry(parent.clone()).or_default();
+            let earlier = replaced
+                .as_ref()
+                .and_then(|old| children.iter().position(|c| c == old));
+            match earlier {
+                Some(i) if !children.contains(&key) => children[i] = key.clone(),
+                Some(i) => {
+                    children.remove(i);
+                }
+                None if !children.contains(&key) => children.push(key.clone()),
+                None => {}
+            }
+        }
+
+        if let Some(old) = &replaced {
+            for conversation in self.conversations.iter_mut() {
+                if conversation.root() == old {
+                    conversation.root = Some(key.clone());
+                }
+            }
+            SEARCH_INDEX.with_borrow_mut(|index| {
+                if let Some(index) = index {
+                    index.remove(old);
+                }
+            });
+        }
+        self.index_message(&key, msg);
+
+        let mut message = msg.clone();
+        message.id = Some(key);
+        self.notify("message", json!({ "message": message }));
+    }
+
+    // Add a message's words and result paths to the search index, once it
+    // has been built
+    fn index_message(&self, id: &str, msg: &Message) {
+        SEARCH_INDEX.with_borrow_mut(|index| {
+            if let Some(index) = index {
+                index.add(id, msg);
+            }
+        });
+    }
+
+    // Index every message of every conversation, oldest first within each
+    // conversation. The children index gives all the ids without loading, so
+    // messages are loaded in batches
+    fn build_search_index(&self) -> SearchIndex {
+        let mut ids: Vec<String> = self
+            .conversations
+            .iter()
+            .map(|c| c.root().to_string())
+            .collect();
+        let mut i = 0;
+        while i < ids.len() {
+            if let Some(children) = self.children.get(&ids[i]) {
+                ids.extend(children.iter().cloned());
+            }
+            i += 1;
+        }
+
+        let mut index = SearchIndex::default();
+        for batch in ids.chunks(SEARCH_INDEX_BATCH) {
+            let messages = self.load_messages(batch).unwrap_or_else(|e| {
+                log(&format!("Failed to load messages to index: {}", e));
+                batch
+                    .iter()
+                    .filter_map(|id| self.load_message(id).ok())
+                    .collect()
+            });
+            for message in &messages {
+                if let Some(id) = &message.id {
+                    index.add(id, message);
+                }
+            }
+        }
+        index
+    }
+
+    // Messages containing every word of `query`, newest first, with a snippet
+    // and the conversation they belong to. The last word may be a prefix
+    fn search_messages(&self, query: &str) -> Result<Vec<Value>, String> {
+        let terms = search_terms(query);
+        if terms.is_empty() {
+            return Err("Empty search query".to_string());
+        }
+
+        if SEARCH_INDEX.with_borrow(Option::is_none) {
+            let index = self.build_search_index();
+            SEARCH_INDEX.set(Some(index));
+        }
+        let ids = SEARCH_INDEX.with_borrow(|index| {
+            index
+                .as_ref()
+                .map(|index| index.lookup(&terms))
+                .unwrap_or_default()
+        });
+
+        let mut roots = HashMap::new();
+        let mut results = Vec::new();
+        for id in ids.iter().take(MESSAGE_SEARCH_MAX_RESULTS) {
+            let Ok(message) = self.load_message(id) else {
+                continue;
+            };
+            let snippet = snippet(&message.content, &terms).or_else(|| {
+                message
+                    .fs_results
                     .iter()
-                    .map(|v| v.as_u64().unwrap_or(0) as u8)
-                    .collect::<Vec<u8>>();
-                let mut msg: Message = serde_json::from_slice(&bytes)?;
-                msg.id = Some(id.to_string());
-                return Ok(msg);
+                    .flatten()
+                    .find(|r| {
+                        search_terms(&r.path)
+                            .iter()
+                            .any(|word| terms.iter().any(|t| word.starts_with(t.as_str())))
+                    })
+                    .map(|r| format!("{} {}", r.operation, r.path))
+            });
+            let root = self.root_of(id, &mut roots);
+            let conversation = self
+                .conversations
+                .iter()
+                .find(|c| Some(c.root()) == root.as_deref());
+            results.push(json!({
+                "message_id": id,
+                "role": message.role,
+                "conversation_id": conversation.map(|c| &c.id),
+                "conversation_title": conversation.and_then(|c| c.title.as_ref()),
+                "snippet": snippet,
+            }));
+        }
+        Ok(results)
+    }
+
+    // The first message of the chain a message is in, remembering the root of
+    // every message passed on the way
+    fn root_of(&self, id: &str, roots: &mut HashMap<String, String>) -> Option<String> {
+        let mut path = Vec::new();
+        let mut current = id.to_string();
+        let root = loop {
+            if let Some(root) = roots.get(&current) {
+                break root.clone();
+            }
+            path.push(current.clone());
+            match self.load_message(&current).ok()?.parent {
+                Some(parent) => current = parent,
+                None => break current,
             }
+        };
+        for id in path {
+            roots.insert(id, root.clone());
+        }
+        Some(root)
+    }
+
+    fn load_message(&self, id: &str) -> Result<Message, Box<dyn std::error::Error>> {
+        Ok(self.load_messages(&[id.to_string()])?.remove(0))
+    }
+
+    // Load several messages, from the cache where possible and with a single
+    // store request for the rest
+    fn load_messages(&self, ids: &[String]) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        let mut messages: Vec<Option<Message>> =
+            MESSAGE_CACHE.with_borrow_mut(|cache| ids.iter().map(|id| cache.get(id)).collect());
+
+        let missing: Vec<String> = ids
+            .iter()
+            .zip(&messages)
+            .filter(|(_, message)| message.is_none())
+            .map(|(id, _)| id.clone())
+            .collect();
+        if !missing.is_empty() {
+            let values = self
+                .store()
+                .get_many(&missing)
+                .map_err(|e| format!("Failed to load message: {}", e))?;
+            let mut loaded = missing
+                .into_iter()
+                .zip(values)
+                .map(|(id, bytes)| decode_message(&bytes, id))
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter();
+            MESSAGE_CACHE.with_borrow_mut(|cache| {
+                for slot in messages.iter_mut().filter(|m| m.is_none()) {
+                    let message = loaded.next().expect("a value for every missing message");
+                    cache.insert(message.clone());
+                    *slot = Some(message);
+                }
+            });
+        }
+
+        Ok(messages.into_iter().flatten().collect())
+    }
+
+    fn get_children(&self, id: &str) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        match self.children.get(id) {
+            Some(ids) => self.load_messages(ids),
+            None => Ok(Vec::new()),
         }
-        Err("Failed to load message".into())
     }
 
     fn get_message_history(&self) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        self.history_from(self.head.clone())
+    }
+
+    // The chain of messages ending at `head`, oldest first
+    fn history_from(
+        &self,
+        head: Option<String>,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error>> {
+        // The children index gives the parent of most messages without loading
+        // them, so the chain is loaded in batches and only a message missing
+        // from the index ends a batch
+        let parents: HashMap<&str, &str> = self
+            .children
+            .iter()
+            .flat_map(|(parent, children)| {
+                children.iter().map(move |c| (c.as_str(), parent.as_str()))
+            })
+            .collect();
+
         let mut messages = Vec::new();
-        let mut current_id = self.head.clone();
+        let mut current_id = head;
 
         while let Some(id) = current_id {
-            let msg = self.load_message(&id)?;
-            messages.push(msg.clone());
-            current_id = msg.parent.clone();
+            let mut batch = vec![id];
+            while let Some(parent) = parents.get(batch[batch.len() - 1].as_str()) {
+                batch.push(parent.to_string());
+            }
+            let loaded = self.load_messages(&batch)?;
+            current_id = loaded.last().and_then(|msg| msg.parent.clone());
+            messages.extend(loaded);
         }
 
         messages.reverse(); // Oldest first
         Ok(messages)
     }
 
+    // The head of a conversation, the current one when no id is given
+    fn conversation_head(&self, id: Option<&str>) -> Result<Option<String>, String> {
+        match id {
+            None => Ok(self.head.clone()),
+            Some(id) => self
+                .conversations
+                .iter()
+                .find(|c| c.id == id)
+                .map(|c| c.head.clone())
+                .ok_or_else(|| format!("Conversation {} not found", id)),
+        }
+    }
+
+    // A message and every message below it
+    fn message_tree(&self, id: &str) -> Result<MessageNode, Box<dyn std::error::Error>> {
+        let message = self.load_message(id)?;
+        let children = self
+            .children
+            .get(id)
+            .into_iter()
+            .flatten()
+            .map(|child| self.message_tree(child))
+            .collect::<Result<_, _>>()?;
+        Ok(MessageNode { message, children })
+    }
+
+    // Export a conversation as a Markdown transcript of its current branch, a
+    // JSON tree of all its branches, or JSONL with one chat per branch
+    fn export_conversation(
+        &self,
+        conversation_id: Option<&str>,
+        format: &str,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let head = self.conversation_head(conversation_id)?;
+        let history = self.history_from(head.clone())?;
+        let tree = match history.first().and_then(|m| m.id.as_deref()) {
+            Some(root) => Some(self.message_tree(root)?),
+            None => None,
+        };
+
+        match format {
+            "markdown" => Ok(export_markdown(&history)),
+            "json" => Ok(serde_json::to_string_pretty(&json!({
+                "conversation_id": conversation_id.map(String::from).or(self.conversation_id.clone()),
+                "head": head,
+                "tree": tree,
+            }))?),
+            "jsonl" => {
+                let mut branches = Vec::new();
+                if let Some(tree) = &tree {
+                    collect_branches(tree, &mut Vec::new(), &mut branches);
+                }
+                let mut lines = String::new();
+                for branch in branches {
+                    let messages: Vec<Value> = branch
+                        .iter()
+                        .map(|m| json!({ "role": m.role, "content": m.content }))
+                        .collect();
+                    lines.push_str(&json!({ "messages": messages }).to_string());
+                    lines.push('\n');
+                }
+                Ok(lines)
+            }
+            _ => Err(format!(
+                "Unknown export format '{}', use markdown, json or jsonl",
+                format
+            )
+            .into()),
+        }
+    }
+
+    // Import a conversation exported elsewhere as a new conversation and make it
+    // current. Takes a JSON tree export (or a bare tree node) or an Anthropic
+    // messages array, bare or as `{"messages": [...]}`. Messages are saved again
+    // with their new parents, so their ids change
+    fn import_conversation(&mut self, body: &Value) -> Result<Value, String> {
+        let messages = body.as_array().or_else(|| body["messages"].as_array());
+        let (root, old_head) = match messages {
+            Some(messages) => (import_messages_array(messages)?, None),
+            None => {
+                let tree = if body.get("tree").is_some() {
+                    &body["tree"]
+                } else {
+                    body
+                };
+                let root: MessageNode = serde_json::from_value(tree.clone())
+                    .map_err(|e| format!("Invalid conversation tree: {}", e))?;
+                (root, body["head"].as_str().map(String::from))
+            }
+        };
+
+        check_import_tree(&root)?;
+
+        // Store every message before anything else changes, so a failed
+        // import leaves the current conversation and the indexes alone
+        let conversation_id = self.next_conversation_id();
+        let mut saved = Vec::new();
+        let mut ids = HashMap::new();
+        let root_id = self.store_node(&root, None, &conversation_id, &mut saved, &mut ids)?;
+
+        for (key, message) in &saved {
+            self.register_message(key, message);
+        }
+        self.start_conversation();
+        self.set_head(root_id.clone());
+
+        // Resume at the exported head, or else at the end of the latest branch
+        let head = match old_head.and_then(|old| ids.get(&old).cloned()) {
+            Some(head) => head,
+            None => {
+                let mut head = root_id;
+                while let Some(child) = self.children.get(&head).and_then(|c| c.last()) {
+                    head = child.clone();
+                }
+                head
+            }
+        };
+        self.set_head(head.clone());
+
+        Ok(json!({
+            "conversation_id": self.conversation_id,
+            "head": head,
+            "imported": saved.len(),
+        }))
+    }
+
+    // Store a message and the messages below it under `parent`, collecting
+    // them in `saved` and mapping their old ids to the new ones
+    fn store_node(
+        &self,
+        node: &MessageNode,
+        parent: Option<String>,
+        conversation_id: &str,
+        saved: &mut Vec<(String, Message)>,
+        ids: &mut HashMap<String, String>,
+    ) -> Result<String, String> {
+        let old_id = node.message.id.clone();
+        let message = imported_message(&node.message, parent, conversation_id);
+        let id = self.store_message(&message).map_err(|e| e.to_string())?;
+        ids.insert(old_id.unwrap_or_else(|| id.clone()), id.clone());
+        saved.push((id.clone(), message));
+
+        for child in &node.children {
+            self.store_node(child, Some(id.clone()), conversation_id, saved, ids)?;
+        }
+        Ok(id)
+    }
+
     fn generate_response(
         &self,
         messages: Vec<Message>,
@@ -415,7 +1940,11 @@ impl State {
                         r.operation,
                         r.path,
                         r.success,
-                        r.data.as_ref().map(|d| format!("\n    <data>{}</data>", d)).unwrap_or_default(),
+                        match (&r.data, &r.media_type) {
+                            (Some(_), Some(media_type)) => format!("\n    <data media-type=\"{}\">attached below</data>", media_type),
+                            (Some(d), None) => format!("\n    <data>{}</data>", d),
+                            (None, _) => String::new(),
+                        },
                         r.error.as_ref().map(|e| format!("\n    <error>{}</error>", e)).unwrap_or_default(),
                     )).collect::<Vec<_>>().join("\n")
                 ))
@@ -428,17 +1957,40 @@ impl State {
         };
         log(&format!("Command results: {}", command_results));
 
+        // Images and PDFs read by the last commands are passed as content blocks
+        let media_blocks: Vec<ContentBlock> = messages
+            .iter()
+            .rev()
+            .take(2)
+            .filter_map(|msg| msg.fs_results.as_ref())
+            .flatten()
+            .filter_map(|r| match (&r.data, &r.media_type) {
+                (Some(data), Some(media_type)) => Some(ContentBlock::media(media_type, data)),
+                _ => None,
+            })
+            .collect();
+
         // Add command results to the user's message content if there are any
+        let last_index = messages.len().saturating_sub(1);
         let messages: Vec<AnthropicMessage> = messages
             .iter()
-            .map(|msg| {
+            .enumerate()
+            .map(|(i, msg)| {
                 let mut content = msg.content.clone();
                 if msg.role == "user" && !command_results.is_empty() {
                     content = format!("{}\n\n{}", content, command_results);
                 }
+
+                let mut blocks = vec![ContentBlock::Text { text: content }];
+                if let Some(attachments) = &msg.attachments {
+                    blocks.extend(attachment_blocks(attachments));
+                }
+                if i == last_index && msg.role == "user" {
+                    blocks.extend(media_blocks.iter().cloned());
+                }
                 AnthropicMessage {
                     role: msg.role.clone(),
-                    content,
+                    content: blocks,
                 }
             })
             .collect();
@@ -455,11 +2007,24 @@ impl State {
 - read-file: Read contents of a file
   Example: <fs-command><operation>read-file</operation><path>example.txt</path></fs-command>
 
+- read-file with a range: <offset> skips that many lines and <limit> caps the lines returned; ranged reads are line numbered. Use <unit>bytes</unit> for byte ranges, <line_numbers>true</line_numbers> to number a whole file. Reads are cut at 100000 bytes with a notice on how to continue
+  Example: <fs-command><operation>read-file</operation><path>src/main.rs</path><offset>100</offset><limit>50</limit></fs-command>
+
+- file-info: Get the size and line count of a file without reading it
+  Example: <fs-command><operation>file-info</operation><path>example.txt</path></fs-command>
+
 - write-file: Write content to a file
   Example: <fs-command><operation>write-file</operation><path>new.txt</path><content>Hello World</content></fs-command>
 
-- list-files: List contents of a directory
+- list-files: List contents of a directory as a tree. Optional <depth> descends into subdirectories, <pattern> filters by glob (* and ? match within a name, ** across directories)
   Example: <fs-command><operation>list-files</operation><path>.</path></fs-command>
+  Example: <fs-command><operation>list-files</operation><path>src</path><depth>3</depth><pattern>*.rs</pattern></fs-command>
+
+- search-files: Find files by name with a glob <pattern>, searching below the path
+  Example: <fs-command><operation>search-files</operation><path>.</path><pattern>**/*.md</pattern></fs-command>
+
+- grep: Search file contents below the path for a regex <pattern>, with optional <include> glob to filter files and <context> lines around matches
+  Example: <fs-command><operation>grep</operation><path>src</path><pattern>fn main</pattern><include>*.rs</include><context>2</context></fs-command>
 
 - create-dir: Create a new directory
   Example: <fs-command><operation>create-dir</operation><path>new_folder</path></fs-command>
@@ -470,11 +2035,27 @@ impl State {
 - edit-file: Edit content in a file by replacing text
   Example: <fs-command><operation>edit-file</operation><path>file.txt</path><old_text>text to find</old_text><new_text>replacement text</new_text></fs-command>
 
+- move-file: Move or rename a file. Fails if the <destination> exists unless <overwrite>true</overwrite> is given
+  Example: <fs-command><operation>move-file</operation><path>old_name.txt</path><destination>new_name.txt</destination></fs-command>
+
+- copy-file: Copy a file to a <destination>, with the same overwrite rule
+  Example: <fs-command><operation>copy-file</operation><path>config.json</path><destination>config.backup.json</destination></fs-command>
+
+- copy-dir: Copy a directory and everything in it to a <destination>, with the same overwrite rule. Directories
+  containing binary files are not copied
+  Example: <fs-command><operation>copy-dir</operation><path>templates</path><destination>templates_v2</destination></fs-command>
+
 - delete-dir: Delete a directory
   Example: <fs-command><operation>delete-dir</operation><path>old_folder</path></fs-command>
 
-Current filesystem root path: {}
-Current permissions: {:?}
+- spawn-agent: Delegate a sub-task to a helper agent that works only within the <path> directory, with the <permissions> listed (a comma separated subset of yours, all of them if omitted). The agent runs until the <task> is done and its final answer is the result
+  Example: <fs-command><operation>spawn-agent</operation><path>docs</path><task>Fix the broken links in every Markdown file</task><permissions>read,write</permissions></fs-command>
+
+To apply several commands atomically, wrap them in one <fs-transaction>...</fs-transaction> block. If any command in the block fails, every change made by the block is rolled back; commands outside it run on their own.
+  Example: <fs-transaction><fs-command><operation>write-file</operation><path>a.txt</path><content>A</content></fs-command><fs-command><operation>edit-file</operation><path>b.txt</path><old_text>old</old_text><new_text>new</new_text></fs-command></fs-transaction>
+
+Mounted filesystems, address a path in a mount as name:relative/path (paths without a mount name are in the first mount):
+{}{}
 
 Remember to:
 1. Be explicit about file operations you're suggesting
@@ -489,23 +2070,45 @@ Be sure to write out only entire files, or information will be lost.
 
 Most importantly, Claude should have fun and enjoy the conversation!
 "#,
-            self.fs_path, self.permissions
+            self.mounts
+                .iter()
+                .map(|m| format!(
+                    "- {}: {} (permissions: {:?})",
+                    m.name, m.path, m.permissions
+                ))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            if self.dry_run {
+                "\nDry-run mode is on: write, edit, delete, move and copy commands are validated and previewed as diffs, but nothing is changed on the filesystem."
+            } else {
+                ""
+            }
         );
         log("Created system message");
 
         // Create messages array without system message
-        let anthropic_messages: Vec<AnthropicMessage> = messages
-            .iter()
-            .map(|msg| AnthropicMessage {
-                role: msg.role.clone(),
-                content: msg.content.clone(),
-            })
-            .collect();
+        let anthropic_messages = messages;
         log(&format!(
             "Created request with {} messages",
             anthropic_messages.len()
         ));
 
+        self.call_model(
+            "claude-3-5-sonnet-20241022",
+            8096,
+            &system_content,
+            &anthropic_messages,
+        )
+    }
+
+    // Send messages to the Anthropic API and return the text of the reply
+    fn call_model(
+        &self,
+        model: &str,
+        max_tokens: u32,
+        system: &str,
+        messages: &[AnthropicMessage],
+    ) -> Result<String, Box<dyn std::error::Error>> {
         // Create HTTP request with system as top-level parameter
         let request = HttpRequest {
             method: "POST".to_string(),
@@ -517,10 +2120,10 @@ Most importantly, Claude should have fun and enjoy the conversation!
             ],
             body: Some(
                 serde_json::to_vec(&json!({
-                    "model": "claude-3-5-sonnet-20241022",
-                    "max_tokens": 8096,
-                    "system": system_content,    // Now a top-level parameter
-                    "messages": anthropic_messages,
+                    "model": model,
+                    "max_tokens": max_tokens,
+                    "system": system,    // Now a top-level parameter
+                    "messages": messages,
                 }))
                 .unwrap(),
             ),
@@ -541,455 +2144,3287 @@ Most importantly, Claude should have fun and enjoy the conversation!
         Err("Failed to generate response".into())
     }
 
-    fn allowed_operation(&self, operation: &str) -> bool {
-        // read:
-        // - read-file
-        // - list-files
-        // write:
-        // - write-file
+    // Give the current conversation a title after its first exchange, and
+    // update its summary every few turns with the cheap model. Failures are
+    // only logged, the conversation goes on without them
+    fn describe_conversation(&mut self) {
+        if self.agent_depth > 0 {
+            return;
+        }
+        let Some(index) = self
+            .conversations
+            .iter()
+            .position(|c| Some(&c.id) == self.conversation_id.as_ref())
+        else {
+            return;
+        };
+        let Ok(messages) = self.get_message_history() else {
+            return;
+        };
+        let turns = messages.iter().filter(|m| m.role == "assistant").count();
+        let conversation = &self.conversations[index];
+
+        if conversation.title.is_none() {
+            let prompt = format!(
+                "Write a title of at most six words for this conversation. Answer with the title only.\n\n{}",
+                transcript(&messages[..messages.len().min(4)])
+            );
+            match self.summarize(&prompt, 30) {
+                Ok(title) => {
+                    let title = title.trim().trim_matches('"').to_string();
+                    self.conversations[index].title = Some(title);
+                }
+                Err(e) => log(&format!("Failed to title conversation: {}", e)),
+            }
+        }
+
+        if turns % SUMMARY_INTERVAL_TURNS == 0 {
+            // Roll the previous summary forward with the turns since it was written
+            let recent = &messages[messages.len().saturating_sub(SUMMARY_INTERVAL_TURNS * 2)..];
+            let prompt = format!(
+                "Summarize this conversation in a short paragraph: what it is about, what was decided and which files were changed. Answer with the summary only.\n\n{}{}",
+                self.conversations[index]
+                    .summary
+                    .as_ref()
+                    .map(|s| format!("Summary of the earlier conversation:\n{}\n\nLatest messages:\n", s))
+                    .unwrap_or_default(),
+                transcript(recent)
+            );
+            match self.summarize(&prompt, 300) {
+                Ok(summary) => self.conversations[index].summary = Some(summary.trim().to_string()),
+                Err(e) => log(&format!("Failed to summarize conversation: {}", e)),
+            }
+        }
+    }
+
+    fn summarize(
+        &self,
+        prompt: &str,
+        max_tokens: u32,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        self.call_model(
+            SUMMARY_MODEL,
+            max_tokens,
+            "You write concise titles and summaries of conversations between a user and an assistant that works on files.",
+            &[AnthropicMessage {
+                role: "user".to_string(),
+                content: vec![ContentBlock::Text {
+                    text: prompt.to_string(),
+                }],
+            }],
+        )
+    }
+
+    // Permissions are per mount, checked against the mount of `path`
+    fn allowed_operation(&self, operation: &str, path: &str) -> bool {
+        // read:
+        // - read-file
+        // - file-info
+        // - list-files
+        // - search-files
+        // - grep
+        // write:
+        // - write-file
+        // - edit-file
         // - create-dir
+        // delete:
         // - delete-file
         // - delete-dir
+        // read + write:
+        // - copy-file
+        // - copy-dir
+        // read + write + delete:
+        // - move-file
+        // read, the agent gets at most the mount's permissions:
+        // - spawn-agent
 
+        let Ok((mount, _)) = self.mount_for(path) else {
+            return false;
+        };
+        let has = |permission: &str| mount.permissions.iter().any(|p| p == permission);
         match operation {
-            "read-file" | "list-files" => self.permissions.contains(&"read".to_string()),
-            "write-file" | "create-dir" => self.permissions.contains(&"write".to_string()),
-            "delete-dir" | "delete-file" => self.permissions.contains(&"delete".to_string()),
+            "read-file" | "file-info" | "list-files" | "search-files" | "grep" => has("read"),
+            "write-file" | "edit-file" | "create-dir" => has("write"),
+            "delete-dir" | "delete-file" => has("delete"),
+            "copy-file" | "copy-dir" => has("read") && has("write"),
+            "move-file" => has("read") && has("write") && has("delete"),
+            "spawn-agent" => has("read"),
             _ => false,
         }
     }
 
-    fn process_fs_commands(&self, commands: Vec<FsCommand>) -> Vec<FsResult> {
-        let mut results = Vec::new();
-
-        for cmd in commands {
-            if !self.allowed_operation(&cmd.operation) {
-                results.push(FsResult {
-                    success: false,
-                    operation: cmd.operation.clone(),
-                    path: cmd.path,
-                    data: None,
-                    error: Some(format!(
-                        "Operation '{}' not permitted, permitted operations: {:?}",
-                        cmd.operation, self.permissions
-                    )),
-                });
-                continue;
-            }
-
-            // Send command to fs-proxy
-            if let Some(fs_proxy_id) = &self.fs_proxy_id {
-                // Resolve the relative path to an absolute path
-                let resolved_path = self.resolve_path(&cmd.path);
-                log(&format!(
-                    "Resolved path '{}' to '{}'",
-                    cmd.path, resolved_path
-                ));
+    // Read the attachments of a send_message command: `attachments` is a list of
+    // sandbox paths read through the fs-proxy, `uploads` a list of
+    // `{"name", "data"}` objects with base64 encoded file contents
+    fn collect_attachments(&self, command: &Value) -> Option<Vec<Attachment>> {
+        let mut attachments = Vec::new();
 
-                let req = json!({
-                    "operation": cmd.operation,
-                    "path": resolved_path,
-                    "content": cmd.content
-                });
+        for path in command["attachments"].as_array().into_iter().flatten() {
+            let Some(path) = path.as_str() else { continue };
+            let bytes = if self.allowed_operation("read-file", path) {
+                self.read_bytes(path)
+            } else {
+                Err(self.permission_error("read-file", path))
+            };
+            attachments.push(match bytes {
+                Ok(bytes) => Attachment::from_bytes(path.to_string(), "file", &bytes),
+                Err(e) => Attachment::failed(path.to_string(), "file", e),
+            });
+        }
 
-                // In the request handling code:
-                match request(fs_proxy_id, &serde_json::to_vec(&req).unwrap()) {
-                    Ok(response) => {
-                        log(&format!("Got response from proxy_id: {:?}", response));
-                        if let Ok(resp) = serde_json::from_slice::<FsResponse>(&response) {
-                            results.push(FsResult {
-                                success: resp.success,
-                                operation: cmd.operation.clone(),
-                                path: cmd.path,
-                                data: match (cmd.operation.as_str(), resp.data) {
-                                    // For list-files, handle vector of strings
-                                    ("list-files", Some(FsResponseData::FileList(files))) => {
-                                        Some(files.join(", "))
-                                    }
-                                    // For read-file, handle string content
-                                    ("read-file", Some(FsResponseData::FileContent(content))) => {
-                                        Some(content)
-                                    }
-                                    // For operations that don't return data
-                                    _ => None,
-                                },
-                                error: resp.error,
-                            });
-                        } else {
-                            results.push(FsResult {
-                                success: false,
-                                operation: cmd.operation,
-                                path: cmd.path,
-                                data: None,
-                                error: Some("Invalid response".to_string()),
-                            });
-                        }
-                    }
+        for upload in command["uploads"].as_array().into_iter().flatten() {
+            let name = upload["name"].as_str().unwrap_or("upload").to_string();
+            attachments.push(
+                match BASE64.decode(upload["data"].as_str().unwrap_or_default()) {
+                    Ok(bytes) => Attachment::from_bytes(name, "upload", &bytes),
                     Err(e) => {
-                        results.push(FsResult {
-                            success: false,
-                            operation: cmd.operation,
-                            path: cmd.path,
-                            data: None,
-                            error: Some(format!("Request failed: {}", e)),
-                        });
+                        Attachment::failed(name, "upload", format!("Invalid base64 data: {}", e))
                     }
+                },
+            );
+        }
+
+        // Drop the content of attachments past the total size limit
+        let mut total = 0;
+        for attachment in attachments.iter_mut() {
+            if let Some(content) = &attachment.content {
+                total += content.len();
+                if total > MAX_ATTACHMENTS_TOTAL_BYTES {
+                    attachment.content = None;
+                    attachment.error = Some(format!(
+                        "Attachments exceed the {} byte total limit",
+                        MAX_ATTACHMENTS_TOTAL_BYTES
+                    ));
                 }
-            } else {
-                results.push(FsResult {
-                    success: false,
-                    operation: cmd.operation,
-                    path: cmd.path,
-                    data: None,
-                    error: Some("Filesystem proxy not available".to_string()),
-                });
             }
         }
 
-        results
+        if attachments.is_empty() {
+            None
+        } else {
+            Some(attachments)
+        }
     }
-}
-
-impl ActorGuest for Component {
-    fn init(data: Option<Json>) -> Json {
-        log("Initializing filesystem chat actor");
-        let data = data.unwrap();
 
-        let init_data: InitData = serde_json::from_slice(&data).unwrap();
-        log(&format!("Store actor id: {}", init_data.store_id));
-        log(&format!("Filesystem path: {}", init_data.fs_path));
-        log(&format!("Permissions: {:?}", init_data.permissions));
-        log(&format!("Websocket port: {}", init_data.websocket_port));
+    fn permission_error(&self, operation: &str, path: &str) -> String {
+        match self.mount_for(path) {
+            Ok((mount, _)) => format!(
+                "Operation '{}' not permitted on mount '{}', permitted operations: {:?}",
+                operation, mount.name, mount.permissions
+            ),
+            Err(e) => e,
+        }
+    }
 
-        // Create and spawn fs-proxy actor
-        let manifest_content = format!(
-            r#"name = "fs-proxy"
-version = "0.1.0"
-description = "A proxy actor that provides controlled access to the filesystem"
-component_path = "/Users/colinrozzi/work/actors/fs-proxy/target/wasm32-unknown-unknown/release/fs_proxy.wasm"
-init_data = "/Users/colinrozzi/work/actors/filesystem-chat/assets/data/fs_proxy.json"
+    // A command needs its operation permitted on the mount of its path, and
+    // write access to the mount of its destination
+    fn check_permission(&self, cmd: &FsCommand) -> Result<(), String> {
+        if !self.allowed_operation(&cmd.operation, &cmd.path) {
+            return Err(self.permission_error(&cmd.operation, &cmd.path));
+        }
+        if let Some(destination) = &cmd.destination {
+            if !self.allowed_operation("write-file", destination) {
+                return Err(self.permission_error("write-file", destination));
+            }
+        }
+        Ok(())
+    }
 
-[interface]
-implements = "ntwk:theater/actor"
-requires = []
+    // Send a single operation to the fs-proxy and decode its response
+    fn fs_request(
+        &self,
+        operation: &str,
+        path: &str,
+        content: Option<&str>,
+    ) -> Result<FsResponse, String> {
+        let (mount, relative_path) = self.mount_for(path)?;
+        if mount.backend == Backend::Builtin {
+            let resolved_path = self.resolve_path(mount, relative_path);
+            return Ok(builtin_fs_request(operation, &resolved_path, content));
+        }
 
-[[handlers]]
-type = "runtime"
-config = {{}}
+        let fs_proxy_id = mount
+            .fs_proxy_id
+            .as_ref()
+            .ok_or_else(|| match mount.status {
+                ProxyStatus::Failed => format!(
+                    "Filesystem proxy for mount '{}' failed after {} restarts",
+                    mount.name, mount.restarts
+                ),
+                _ => format!("Filesystem proxy for mount '{}' is restarting", mount.name),
+            })?;
 
-[[handlers]]
-type = "filesystem"
-config = {{ path = "{}" }}
-"#,
-            init_data.fs_path
-        );
+        // Resolve the relative path to an absolute path
+        let resolved_path = self.resolve_path(mount, relative_path);
+        log(&format!("Resolved path '{}' to '{}'", path, resolved_path));
 
-        // Write init data to data directory
-        // init data should contain the permissions the fs-proxy should be started with
-        let init_data_path = "data/fs_proxy.json";
-        let fs_proxy_init_data = json!({
-            "permissions": init_data.permissions
+        let req = json!({
+            "operation": operation,
+            "path": resolved_path,
+            "content": content
         });
-        if let Err(e) = write_file(
-            init_data_path,
-            &serde_json::to_string(&fs_proxy_init_data).unwrap(),
-        ) {
-            log(&format!("Failed to create init data: {}", e));
-            return vec![];
-        }
-
-        // Write manifest to data directory
-        let manifest_path = "data/fs_proxy.toml";
-        if let Err(e) = write_file(manifest_path, &manifest_content) {
-            log(&format!("Failed to create manifest: {}", e));
-            return vec![];
-        }
 
-        let full_manifest_path =
-            "/Users/colinrozzi/work/actors/filesystem-chat/assets/data/fs_proxy.toml";
+        let response = request(fs_proxy_id, &serde_json::to_vec(&req).unwrap())
+            .map_err(|e| format!("Request failed: {}", e))?;
+        log(&format!("Got response from proxy_id: {:?}", response));
 
-        // Spawn fs-proxy actor
-        let fs_proxy_id = spawn(full_manifest_path);
-        log(&format!("Spawned fs-proxy actor: {}", fs_proxy_id));
+        serde_json::from_slice::<FsResponse>(&response).map_err(|_| "Invalid response".to_string())
+    }
 
-        // Read API key
-        log("Reading API key");
-        let res = read_file("api-key.txt");
-        if res.is_err() {
-            log("Failed to read API key");
-            return vec![];
+    fn execute_fs_command(&self, cmd: FsCommand) -> FsResult {
+        if let Err(error) = self.check_permission(&cmd) {
+            return FsResult::failure(cmd.operation, cmd.path, error);
         }
-        let api_key = res.unwrap();
-        log("API key read");
-        let api_key = String::from_utf8(api_key).unwrap().trim().to_string();
-        log("API key loaded");
 
-        let initial_state = State {
-            store_id: init_data.store_id,
-            fs_proxy_id: Some(fs_proxy_id),
-            fs_path: init_data.fs_path,
-            permissions: init_data.permissions,
-            head: None,
-            websocket_port: init_data.websocket_port,
-            api_key,
-        };
+        match cmd.operation.as_str() {
+            "read-file" => return self.read_file(cmd),
+            "file-info" => return self.file_info(cmd),
+            "list-files" => return self.list_files(cmd),
+            "search-files" => return self.search_files(cmd),
+            "grep" => return self.grep(cmd),
+            "edit-file" => return self.edit_file(cmd),
+            "move-file" | "copy-file" | "copy-dir" => return self.transfer(cmd),
+            "spawn-agent" => return self.spawn_agent(cmd),
+            _ => {}
+        }
 
-        serde_json::to_vec(&initial_state).unwrap()
+        // Send command to fs-proxy, these operations don't return data
+        match self.fs_request(&cmd.operation, &cmd.path, cmd.content.as_deref()) {
+            Ok(resp) => FsResult {
+                success: resp.success,
+                error: resp.error,
+                ..FsResult::succeeded(cmd.operation, cmd.path, None)
+            },
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
     }
-}
 
-impl HttpGuest for Component {
-    fn handle_request(request: HttpRequest, state: Json) -> (HttpResponse, Json) {
-        let state: State = serde_json::from_slice(&state).unwrap();
+    fn read_file(&self, cmd: FsCommand) -> FsResult {
+        let bytes = match self.read_bytes(&cmd.path) {
+            Ok(bytes) => bytes,
+            Err(e) => return FsResult::failure(cmd.operation, cmd.path, e),
+        };
+        if is_binary(&bytes) {
+            let (data, media_type) = file_content_data(&cmd.path, bytes);
+            return FsResult {
+                media_type,
+                ..FsResult::succeeded(cmd.operation, cmd.path, data)
+            };
+        }
 
-        let response = match (request.method.as_str(), request.uri.as_str()) {
-            ("GET", "/") | ("GET", "/index.html") => match read_file("index.html") {
-                Ok(content) => HttpResponse {
-                    status: 200,
-                    headers: vec![("Content-Type".to_string(), "text/html".to_string())],
-                    body: Some(content),
-                },
-                Err(e) => HttpResponse {
-                    status: 500,
-                    headers: vec![],
-                    body: Some(format!("Failed to read index.html: {}", e).into_bytes()),
-                },
-            },
-            ("GET", "/styles.css") => match read_file("styles.css") {
-                Ok(content) => HttpResponse {
-                    status: 200,
-                    headers: vec![("Content-Type".to_string(), "text/css".to_string())],
-                    body: Some(content),
-                },
-                Err(e) => HttpResponse {
-                    status: 500,
-                    headers: vec![],
-                    body: Some(format!("Failed to read styles.css: {}", e).into_bytes()),
-                },
-            },
-            ("GET", "/chat.js") => match read_file("chat.js") {
-                Ok(content) => {
-                    let str_content = String::from_utf8(content).unwrap();
-                    let content = str_content
-                        .replace("{{WEBSOCKET_PORT}}", &format!("{}", state.websocket_port));
-                    HttpResponse {
-                        status: 200,
-                        headers: vec![(
-                            "Content-Type".to_string(),
-                            "application/javascript".to_string(),
-                        )],
-                        body: Some(content.into_bytes()),
-                    }
-                }
-                Err(e) => HttpResponse {
-                    status: 500,
-                    headers: vec![],
-                    body: Some(format!("Failed to read chat.js: {}", e).into_bytes()),
-                },
-            },
-            ("GET", "/api/messages") => match state.get_message_history() {
-                Ok(messages) => HttpResponse {
-                    status: 200,
-                    headers: vec![("Content-Type".to_string(), "application/json".to_string())],
-                    body: Some(
-                        serde_json::to_vec(&json!({
-                            "type": "message_update",
-                            "messages": messages
-                        }))
-                        .unwrap(),
-                    ),
-                },
-                Err(e) => HttpResponse {
-                    status: 500,
-                    headers: vec![],
-                    body: Some(format!("Failed to load messages: {}", e).into_bytes()),
-                },
-            },
-            _ => HttpResponse {
-                status: 404,
-                headers: vec![],
-                body: Some(b"Not Found".to_vec()),
-            },
+        let text = String::from_utf8(bytes).unwrap_or_default();
+        let data = match cmd.unit.as_deref().unwrap_or("lines") {
+            "lines" => read_lines(&text, &cmd),
+            "bytes" => read_bytes_range(&text, &cmd),
+            unit => {
+                return FsResult::failure(
+                    cmd.operation,
+                    cmd.path,
+                    format!("Unknown unit '{}', expected 'lines' or 'bytes'", unit),
+                )
+            }
         };
+        FsResult::succeeded(cmd.operation, cmd.path, Some(data))
+    }
 
-        (response, serde_json::to_vec(&state).unwrap())
+    // Report the size and line count of a file without its contents
+    fn file_info(&self, cmd: FsCommand) -> FsResult {
+        if self.list_dir(&cmd.path).is_ok() {
+            let data = json!({ "kind": "dir" }).to_string();
+            return FsResult::succeeded(cmd.operation, cmd.path, Some(data));
+        }
+
+        match self.read_bytes(&cmd.path) {
+            Ok(bytes) => {
+                let binary = is_binary(&bytes);
+                let info = json!({
+                    "kind": "file",
+                    "size": bytes.len(),
+                    "lines": if binary { None } else { Some(count_lines(&bytes)) },
+                    "binary": binary,
+                    "media_type": media_type_for(&cmd.path, &bytes),
+                });
+                FsResult::succeeded(cmd.operation, cmd.path, Some(info.to_string()))
+            }
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
     }
-}
 
-impl WebSocketGuest for Component {
-    fn handle_message(message: WebsocketMessage, state: Json) -> (Json, WebsocketResponse) {
-        log(&format!("Received message: {:?}", message));
-        let mut state: State = serde_json::from_slice(&state).unwrap();
+    // Replace the single occurrence of old_text in a file with new_text
+    fn edit_file(&self, cmd: FsCommand) -> FsResult {
+        let (Some(old_text), Some(new_text)) = (&cmd.old_text, &cmd.new_text) else {
+            let error = "Missing <old_text> or <new_text>".to_string();
+            return FsResult::failure(cmd.operation, cmd.path, error);
+        };
 
-        match message.ty {
-            MessageType::Text => {
-                if let Some(text) = message.text {
-                    if let Ok(command) = serde_json::from_str::<Value>(&text) {
-                        match command["type"].as_str() {
-                            Some("send_message") => {
-                                log("Matched send_message command");
-                                if let Some(content) = command["content"].as_str() {
-                                    log(&format!("Processing content: {}", content));
+        let outcome = self.read_bytes(&cmd.path).and_then(|bytes| {
+            if is_binary(&bytes) {
+                return Err(format!(
+                    "{} is a binary file and cannot be edited",
+                    cmd.path
+                ));
+            }
+            let text = String::from_utf8(bytes).unwrap_or_default();
+            let edited = apply_edit(&cmd.path, &text, old_text, new_text)?;
+            self.fs_op("write-file", &cmd.path, Some(&edited))
+        });
+        match outcome {
+            Ok(()) => FsResult::succeeded(cmd.operation, cmd.path, None),
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
+    }
 
-                                    // Create initial message state
-                                    let mut message_state = MessageState {
-                                        message: Message::new(
-                                            "user".to_string(),
-                                            content.to_string(),
-                                            state.head.clone(),
-                                        ),
-                                        status: MessageStatus::Pending,
-                                        retries: 0,
-                                        last_error: None,
-                                    };
+    // Run the filesystem commands of a message, as a transaction when it asks
+    // for one
+    fn run_fs_commands(&self, message: &mut Message) {
+        let Some(commands) = message.fs_commands.clone() else {
+            return;
+        };
 
-                                    // Extract filesystem commands if present
-                                    if let Some(fs_commands) = command["fs_commands"].as_array() {
-                                        let commands: Vec<FsCommand> = fs_commands
-                                            .iter()
-                                            .filter_map(|cmd| {
-                                                serde_json::from_value(cmd.clone()).ok()
-                                            })
-                                            .collect();
-
-                                        if !commands.is_empty() {
-                                            message_state.message.fs_commands = Some(commands);
-                                        }
-                                    }
+        // Previews don't see each other's changes, and a previewed transaction
+        // stays staged
+        if self.dry_run {
+            message.fs_results = Some(
+                commands
+                    .into_iter()
+                    .map(|cmd| self.preview_command(cmd))
+                    .collect(),
+            );
+        } else if let Some(staged) = &message.transaction {
+            let mut before = commands;
+            let range = staged.commands.clone().unwrap_or(0..before.len());
+            let after = before.split_off(range.end.min(before.len()));
+            let inside = before.split_off(range.start.min(before.len()));
 
-                                    // Save initial message and process
-                                    if let Ok(msg_id) = state.save_message(&message_state.message) {
-                                        message_state.message.id = Some(msg_id.clone());
-                                        state.head = Some(msg_id);
-
-                                        match state.process_message(&mut message_state) {
-                                            Ok(()) => {
-                                                return send_message_state_update(
-                                                    state,
-                                                    message_state,
-                                                );
-                                            }
-                                            Err(error) => {
-                                                state.handle_retry(&mut message_state, &error);
-                                                return send_message_state_update(
-                                                    state,
-                                                    message_state,
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Some("retry_message") => {
-                                if let Some(message_id) = command["messageId"].as_str() {
-                                    if let Ok(message) = state.load_message(message_id) {
-                                        let mut message_state = MessageState {
-                                            message,
-                                            status: MessageStatus::Pending,
-                                            retries: 0,
-                                            last_error: None,
-                                        };
-
-                                        match state.process_message(&mut message_state) {
-                                            Ok(()) => {
-                                                return send_message_state_update(
-                                                    state,
-                                                    message_state,
-                                                );
-                                            }
-                                            Err(error) => {
-                                                state.handle_retry(&mut message_state, &error);
-                                                return send_message_state_update(
-                                                    state,
-                                                    message_state,
-                                                );
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                            Some("get_messages") => {
-                                if let Ok(messages) = state.get_message_history() {
-                                    return (
-                                        serde_json::to_vec(&state).unwrap(),
-                                        WebsocketResponse {
-                                            messages: vec![WebsocketMessage {
-                                                ty: MessageType::Text,
-                                                text: Some(
-                                                    json!({
-                                                        "type": "message_update",
-                                                        "messages": messages
-                                                    })
-                                                    .to_string(),
-                                                ),
-                                                data: None,
-                                            }],
-                                        },
-                                    );
-                                }
-                            }
-                            _ => {
-                                log("Unknown command type received");
-                            }
-                        }
-                    }
-                }
-            }
-            _ => {}
+            let mut results = self.process_fs_commands(before);
+            let (inside_results, mut transaction) = self.process_transaction(inside, range.start);
+            results.extend(inside_results);
+            results.extend(self.process_fs_commands(after));
+            transaction.commands = staged.commands.clone();
+            message.fs_results = Some(results);
+            message.transaction = Some(transaction);
+        } else {
+            message.fs_results = Some(self.process_fs_commands(commands));
         }
 
-        (
-            serde_json::to_vec(&state).unwrap(),
-            WebsocketResponse { messages: vec![] },
-        )
+        if !self.dry_run {
+            self.notify_mutations(&message.role, message.fs_results.iter().flatten());
+        }
     }
-}
+
+    // Tell subscribers about the commands that changed the filesystem
+    fn notify_mutations<'a>(&self, role: &str, results: impl Iterator<Item = &'a FsResult>) {
+        let mutations: Vec<&FsResult> = results
+            .filter(|r| r.success && is_mutation(&r.operation))
+            .collect();
+        if !mutations.is_empty() {
+            self.notify("fs_mutation", json!({ "role": role, "results": mutations }));
+        }
+    }
+
+    fn process_fs_commands(&self, commands: Vec<FsCommand>) -> Vec<FsResult> {
+        commands
+            .into_iter()
+            .map(|cmd| self.execute_with_snapshots(cmd))
+            .collect()
+    }
+
+    // Execute a command after saving pre-images of the paths it changes to the
+    // store, so the change can be undone. Paths that can't be snapshotted (e.g.
+    // binary files) are changed without one
+    fn execute_with_snapshots(&self, cmd: FsCommand) -> FsResult {
+        let mut snapshots = Vec::new();
+        let mut hashes = Vec::new();
+        if self.check_permission(&cmd).is_ok() {
+            for path in self.affected_paths(&cmd) {
+                hashes.push(PathHashes {
+                    before: self.content_hash(&path),
+                    after: None,
+                    path: path.clone(),
+                });
+                let key = self.snapshot(&path).and_then(|pre_image| {
+                    let bytes = serde_json::to_vec(&pre_image).map_err(|e| e.to_string())?;
+                    self.put_blob(bytes).map_err(|e| e.to_string())
+                });
+                match key {
+                    Ok(key) => snapshots.push(Snapshot { path, key }),
+                    Err(e) => log(&format!("No snapshot of {}: {}", path, e)),
+                }
+            }
+        }
+
+        let mut result = self.execute_fs_command(cmd);
+        if result.success && !snapshots.is_empty() {
+            result.snapshots = Some(snapshots);
+        }
+        if result.success && !hashes.is_empty() {
+            for hash in hashes.iter_mut() {
+                hash.after = self.content_hash(&hash.path);
+            }
+            result.hashes = Some(hashes);
+        }
+        result
+    }
+
+    // SHA-1 of a file, or for a directory of the paths and hashes of the files
+    // in it. None when the path doesn't exist or is too large to hash
+    fn content_hash(&self, path: &str) -> Option<String> {
+        if let Ok(bytes) = self.read_bytes(path) {
+            return Some(sha1_hex(&bytes));
+        }
+        let mut budget = LIST_FILES_MAX_ENTRIES;
+        let entries = self
+            .list_entries(path, path, SEARCH_MAX_DEPTH, None, &mut budget)
+            .ok()?;
+        let mut listing = String::new();
+        for entry in entries {
+            let hash = match entry.kind.as_str() {
+                "dir" => "dir".to_string(),
+                _ => sha1_hex(&self.read_bytes(&entry.path).ok()?),
+            };
+            listing.push_str(&format!("{}\t{}\n", relative_to(path, &entry.path), hash));
+        }
+        Some(sha1_hex(listing.as_bytes()))
+    }
+
+    // Append an audit record for each path changed by the results of a stored
+    // message
+    fn record_audit<'a>(
+        &mut self,
+        message_id: &str,
+        role: &str,
+        results: impl Iterator<Item = &'a FsResult>,
+    ) {
+        let changes: Vec<(&FsResult, &PathHashes)> = results
+            .filter(|r| r.success && is_mutation(&r.operation))
+            .flat_map(|r| r.hashes.iter().flatten().map(move |h| (r, h)))
+            .collect();
+        if changes.is_empty() {
+            return;
+        }
+
+        let chain_event = get_chain()
+            .events
+            .last()
+            .map(|e| format!("{:016x}", e.hash));
+        for (result, hashes) in changes {
+            let mut record = AuditRecord {
+                sequence: self.audit_head.as_ref().map_or(0, |h| h.sequence + 1),
+                message_id: message_id.to_string(),
+                role: role.to_string(),
+                operation: result.operation.clone(),
+                path: hashes.path.clone(),
+                before: hashes.before.clone(),
+                after: hashes.after.clone(),
+                chain_event: chain_event.clone(),
+                previous_key: self.audit_head.as_ref().map(|h| h.key.clone()),
+                previous_hash: self.audit_head.as_ref().map(|h| h.hash.clone()),
+                hash: String::new(),
+            };
+            record.hash = record.compute_hash();
+            match self.put_blob(serde_json::to_vec(&record).unwrap()) {
+                Ok(key) => {
+                    self.audit_head = Some(AuditHead {
+                        key,
+                        hash: record.hash,
+                        sequence: record.sequence,
+                    })
+                }
+                Err(e) => log(&format!("Failed to store audit record: {}", e)),
+            }
+        }
+    }
+
+    // The latest audit records, newest first
+    fn audit_records(&self, limit: usize) -> Result<Vec<AuditRecord>, String> {
+        let mut records = Vec::new();
+        let mut key = self.audit_head.as_ref().map(|h| h.key.clone());
+        while let Some(k) = key {
+            if records.len() >= limit {
+                break;
+            }
+            let bytes = self.get_blob(&k).map_err(|e| e.to_string())?;
+            let record: AuditRecord = serde_json::from_slice(&bytes).map_err(|e| e.to_string())?;
+            key = record.previous_key.clone();
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    // Check the audit log from its head. Returns the number of records checked
+    fn verify_audit(&self) -> Result<u64, String> {
+        match &self.audit_head {
+            Some(head) => {
+                verify_audit_chain(head, |key| self.get_blob(key).map_err(|e| e.to_string()))
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Work out what a command would change without touching the filesystem.
+    // Read-only commands run as usual
+    fn preview_command(&self, cmd: FsCommand) -> FsResult {
+        if let Err(e) = self.validate_command(&cmd) {
+            return FsResult::failure(cmd.operation, cmd.path, e);
+        }
+        if cmd.operation == "spawn-agent" {
+            let data = format!(
+                "Dry run, no agent was spawned. It would work in {} on: {}",
+                cmd.path,
+                cmd.task.unwrap_or_default()
+            );
+            return FsResult::succeeded(cmd.operation, cmd.path, Some(data));
+        }
+        let outcome = match cmd.operation.as_str() {
+            "write-file" => self.preview_write(&cmd),
+            "edit-file" => self.preview_edit(&cmd),
+            "delete-file" => self.preview_delete_file(&cmd.path),
+            "create-dir" => self.preview_create_dir(&cmd.path),
+            "delete-dir" => self.preview_delete_dir(&cmd.path),
+            "move-file" | "copy-file" | "copy-dir" => self.preview_transfer(&cmd),
+            _ => return self.execute_fs_command(cmd),
+        };
+
+        match outcome {
+            Ok(previews) => {
+                let mut summary = "Dry run, nothing was changed".to_string();
+                for preview in &previews {
+                    summary.push_str(&format!("\n{} {}", preview.status, preview.path));
+                    if !preview.diff.is_empty() {
+                        summary.push_str(&format!("\n{}", preview.diff.trim_end()));
+                    }
+                }
+                FsResult {
+                    preview: Some(previews),
+                    ..FsResult::succeeded(cmd.operation, cmd.path, Some(summary))
+                }
+            }
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
+    }
+
+    // Current text of a file for a preview, None when there is no such file.
+    // Binary files can't be diffed and show as a placeholder line
+    fn preview_text(&self, path: &str) -> Option<String> {
+        let bytes = self.read_bytes(path).ok()?;
+        if is_binary(&bytes) {
+            Some(format!("[binary file, {} bytes]\n", bytes.len()))
+        } else {
+            String::from_utf8(bytes).ok()
+        }
+    }
+
+    fn preview_write(&self, cmd: &FsCommand) -> Result<Vec<FilePreview>, String> {
+        if self.list_dir(&cmd.path).is_ok() {
+            return Err(format!("{} is a directory", cmd.path));
+        }
+        let old = self.preview_text(&cmd.path);
+        Ok(vec![file_preview(
+            &cmd.path,
+            old.as_deref(),
+            cmd.content.as_deref(),
+        )])
+    }
+
+    fn preview_edit(&self, cmd: &FsCommand) -> Result<Vec<FilePreview>, String> {
+        let (Some(old_text), Some(new_text)) = (&cmd.old_text, &cmd.new_text) else {
+            return Err("Missing <old_text> or <new_text>".to_string());
+        };
+        let bytes = self.read_bytes(&cmd.path)?;
+        if is_binary(&bytes) {
+            return Err(format!(
+                "{} is a binary file and cannot be edited",
+                cmd.path
+            ));
+        }
+        let text = String::from_utf8(bytes).unwrap_or_default();
+        let edited = apply_edit(&cmd.path, &text, old_text, new_text)?;
+        Ok(vec![file_preview(&cmd.path, Some(&text), Some(&edited))])
+    }
+
+    fn preview_delete_file(&self, path: &str) -> Result<Vec<FilePreview>, String> {
+        if self.list_dir(path).is_ok() {
+            return Err(format!("{} is a directory, use delete-dir", path));
+        }
+        let old = self
+            .preview_text(path)
+            .ok_or_else(|| format!("{} does not exist", path))?;
+        Ok(vec![file_preview(path, Some(&old), None)])
+    }
+
+    fn preview_create_dir(&self, path: &str) -> Result<Vec<FilePreview>, String> {
+        let status = if self.list_dir(path).is_ok() {
+            "unchanged"
+        } else if self.read_bytes(path).is_ok() {
+            return Err(format!("{} is a file", path));
+        } else {
+            "added"
+        };
+        Ok(vec![FilePreview {
+            path: path.to_string(),
+            status: status.to_string(),
+            diff: String::new(),
+        }])
+    }
+
+    // Every file below the directory is listed as deleted
+    fn preview_delete_dir(&self, path: &str) -> Result<Vec<FilePreview>, String> {
+        let mut budget = LIST_FILES_MAX_ENTRIES;
+        let entries = self.list_entries(path, path, SEARCH_MAX_DEPTH, None, &mut budget)?;
+        let mut previews = vec![FilePreview {
+            path: path.to_string(),
+            status: "deleted".to_string(),
+            diff: String::new(),
+        }];
+        for entry in entries.iter().filter(|e| e.kind != "dir") {
+            let old = self.preview_text(&entry.path);
+            previews.push(file_preview(&entry.path, old.as_deref(), None));
+        }
+        Ok(previews)
+    }
+
+    fn preview_transfer(&self, cmd: &FsCommand) -> Result<Vec<FilePreview>, String> {
+        let destination = self.check_transfer(cmd)?;
+        // Pairs of source file and the path it is copied to
+        let files = if cmd.operation == "copy-dir" {
+            let mut budget = LIST_FILES_MAX_ENTRIES;
+            self.list_entries(&cmd.path, &cmd.path, SEARCH_MAX_DEPTH, None, &mut budget)?
+                .into_iter()
+                .filter(|e| e.kind != "dir")
+                .map(|e| {
+                    let target = join_path(&destination, relative_to(&cmd.path, &e.path));
+                    (e.path, target)
+                })
+                .collect()
+        } else {
+            vec![(cmd.path.clone(), destination)]
+        };
+
+        let mut previews = Vec::new();
+        for (source, target) in files {
+            let bytes = self.read_bytes(&source)?;
+            if is_binary(&bytes) {
+                return Err(format!("{} is a binary file and cannot be copied", source));
+            }
+            let content = String::from_utf8(bytes).unwrap_or_default();
+            let old = self.preview_text(&target);
+            previews.push(file_preview(&target, old.as_deref(), Some(&content)));
+            if cmd.operation == "move-file" {
+                previews.push(file_preview(&source, Some(&content), None));
+            }
+        }
+        Ok(previews)
+    }
+
+    // Aggregate the changes made by every message in the current history into
+    // one diff per file
+    fn change_log(&self) -> Result<Vec<FileChange>, Box<dyn std::error::Error>> {
+        // First snapshot of each changed path, and the operations that touched it
+        let mut changed: Vec<(Snapshot, Vec<ChangeOperation>)> = Vec::new();
+        for message in self.get_message_history()? {
+            for result in message.fs_results.iter().flatten().filter(|r| r.success) {
+                for snapshot in result.snapshots.iter().flatten() {
+                    let operation = ChangeOperation {
+                        message_id: message.id.clone(),
+                        operation: result.operation.clone(),
+                        path: result.path.clone(),
+                    };
+                    match changed.iter_mut().find(|(s, _)| s.path == snapshot.path) {
+                        Some((_, operations)) => operations.push(operation),
+                        None => changed.push((snapshot.clone(), vec![operation])),
+                    }
+                }
+            }
+        }
+
+        let mut changes = Vec::new();
+        for (snapshot, operations) in changed {
+            // A snapshot that can't be loaded leaves its path out rather than
+            // the whole log
+            let pre_image = self
+                .get_blob(&snapshot.key)
+                .map_err(|e| e.to_string())
+                .and_then(|blob| serde_json::from_slice(&blob).map_err(|e| e.to_string()));
+            let pre_image: PreImage = match pre_image {
+                Ok(pre_image) => pre_image,
+                Err(e) => {
+                    log(&format!("Skipping snapshot {}: {}", snapshot.key, e));
+                    continue;
+                }
+            };
+
+            // Directories are compared file by file
+            let listed = !matches!(pre_image, PreImage::File(_));
+            let (mut before, known): (Vec<(String, Option<String>)>, HashSet<String>) =
+                match pre_image {
+                    PreImage::Missing => (
+                        vec![(snapshot.path.clone(), None)],
+                        HashSet::from([snapshot.path.clone()]),
+                    ),
+                    PreImage::File(content) => {
+                        (vec![(snapshot.path.clone(), Some(content))], HashSet::new())
+                    }
+                    PreImage::Dir(contents) => {
+                        let contents: Vec<(String, Option<String>)> = contents
+                            .into_iter()
+                            .map(|(relative, content)| {
+                                (join_path(&snapshot.path, &relative), content)
+                            })
+                            .collect();
+                        let known = contents.iter().map(|(path, _)| path.clone()).collect();
+                        let text = contents
+                            .into_iter()
+                            .filter(|(_, content)| content.is_some());
+                        (text.collect(), known)
+                    }
+                };
+
+            // Files that are there now but weren't, e.g. the destination of a
+            // copy-dir or new files in a directory an agent worked in
+            if listed {
+                if let Ok((files, _)) = self.find_files(&snapshot.path, None, None) {
+                    before.extend(
+                        files
+                            .into_iter()
+                            .filter(|file| !known.contains(&file.path))
+                            .map(|file| (file.path, None)),
+                    );
+                }
+            }
+
+            for (path, old) in before {
+                let new = match self.read_bytes(&path) {
+                    Ok(bytes) if !is_binary(&bytes) => Some(String::from_utf8(bytes)?),
+                    _ => None,
+                };
+                let Some(status) = change_status(old.as_deref(), new.as_deref()) else {
+                    continue;
+                };
+                // Of a directory only the files that changed are listed
+                if listed && status == "unchanged" {
+                    continue;
+                }
+                changes.push(FileChange {
+                    diff: unified_diff(&path, old.as_deref(), new.as_deref()),
+                    status: status.to_string(),
+                    operations: operations.clone(),
+                    path,
+                });
+            }
+        }
+        Ok(changes)
+    }
+
+    // Restore every path a message changed to its content before the message
+    fn undo_message(&mut self, message_id: &str) -> Result<Vec<FsResult>, String> {
+        let message = self
+            .load_message(message_id)
+            .map_err(|e| format!("Failed to load message {}: {}", message_id, e))?;
+
+        // The first snapshot of a path is its state before the message
+        let mut snapshots: Vec<Snapshot> = Vec::new();
+        for result in message.fs_results.iter().flatten().filter(|r| r.success) {
+            for snapshot in result.snapshots.iter().flatten() {
+                if !snapshots.iter().any(|s| s.path == snapshot.path) {
+                    snapshots.push(snapshot.clone());
+                }
+            }
+        }
+        if snapshots.is_empty() {
+            return Err(format!("Message {} has no changes to undo", message_id));
+        }
+
+        let results = snapshots
+            .into_iter()
+            .rev()
+            .map(|snapshot| {
+                let before = self.content_hash(&snapshot.path);
+                let restored = self
+                    .get_blob(&snapshot.key)
+                    .map_err(|e| e.to_string())
+                    .and_then(|bytes| {
+                        serde_json::from_slice::<PreImage>(&bytes).map_err(|e| e.to_string())
+                    })
+                    .and_then(|pre_image| {
+                        let operation = match pre_image {
+                            PreImage::Missing => "delete-file",
+                            _ => "write-file",
+                        };
+                        if !self.allowed_operation(operation, &snapshot.path) {
+                            return Err(self.permission_error(operation, &snapshot.path));
+                        }
+                        self.restore(&snapshot.path, &pre_image)
+                    });
+                match restored {
+                    Ok(()) => FsResult {
+                        hashes: Some(vec![PathHashes {
+                            path: snapshot.path.clone(),
+                            before,
+                            after: self.content_hash(&snapshot.path),
+                        }]),
+                        ..FsResult::succeeded("undo".to_string(), snapshot.path, None)
+                    },
+                    Err(e) => FsResult::failure("undo".to_string(), snapshot.path, e),
+                }
+            })
+            .collect::<Vec<_>>();
+        self.record_audit(message_id, "undo", results.iter());
+        self.notify_mutations("undo", results.iter());
+        Ok(results)
+    }
+
+    // Check a command can run before anything in its transaction is applied
+    fn validate_command(&self, cmd: &FsCommand) -> Result<(), String> {
+        self.check_permission(cmd)?;
+        match cmd.operation.as_str() {
+            "write-file" if cmd.content.is_none() => Err("Missing <content>".to_string()),
+            "edit-file" if cmd.old_text.is_none() || cmd.new_text.is_none() => {
+                Err("Missing <old_text> or <new_text>".to_string())
+            }
+            "move-file" | "copy-file" | "copy-dir" if cmd.destination.is_none() => {
+                Err("Missing <destination>".to_string())
+            }
+            "spawn-agent" if cmd.task.is_none() => Err("Missing <task>".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    // The paths a command changes, empty for read-only operations
+    fn affected_paths(&self, cmd: &FsCommand) -> Vec<String> {
+        match cmd.operation.as_str() {
+            "write-file" | "edit-file" | "delete-file" | "create-dir" | "delete-dir"
+            | "spawn-agent" => vec![cmd.path.clone()],
+            "move-file" => {
+                let mut paths = vec![cmd.path.clone()];
+                paths.extend(self.transfer_destination(cmd));
+                paths
+            }
+            "copy-file" | "copy-dir" => self.transfer_destination(cmd).into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn snapshot(&self, path: &str) -> Result<PreImage, String> {
+        if let Ok(bytes) = self.read_bytes(path) {
+            if is_binary(&bytes) {
+                return Err(format!("{} is a binary file and cannot be restored", path));
+            }
+            return Ok(PreImage::File(String::from_utf8(bytes).unwrap_or_default()));
+        }
+        if self.list_dir(path).is_err() {
+            return Ok(PreImage::Missing);
+        }
+
+        let mut budget = LIST_FILES_MAX_ENTRIES;
+        let entries = self.list_entries(path, path, SEARCH_MAX_DEPTH, None, &mut budget)?;
+        if budget == 0 {
+            return Err(format!("{} is too large to snapshot", path));
+        }
+        let mut contents = Vec::new();
+        for entry in entries {
+            let content = if entry.kind == "dir" {
+                None
+            } else {
+                let bytes = self.read_bytes(&entry.path)?;
+                if is_binary(&bytes) {
+                    return Err(format!(
+                        "{} is a binary file and cannot be restored",
+                        entry.path
+                    ));
+                }
+                Some(String::from_utf8(bytes).unwrap_or_default())
+            };
+            contents.push((relative_to(path, &entry.path).to_string(), content));
+        }
+        Ok(PreImage::Dir(contents))
+    }
+
+    // Put a path back the way its pre-image recorded it. Directories get their
+    // recorded files back; entries added since are left in place
+    fn restore(&self, path: &str, pre_image: &PreImage) -> Result<(), String> {
+        match pre_image {
+            PreImage::Missing if self.list_dir(path).is_ok() => {
+                self.fs_op("delete-dir", path, None)
+            }
+            PreImage::Missing if self.read_bytes(path).is_ok() => {
+                self.fs_op("delete-file", path, None)
+            }
+            PreImage::Missing => Ok(()),
+            PreImage::File(content) => self.fs_op("write-file", path, Some(content)),
+            PreImage::Dir(contents) => {
+                if self.list_dir(path).is_err() {
+                    self.fs_op("create-dir", path, None)?;
+                }
+                for (relative, content) in contents {
+                    let target = join_path(path, relative);
+                    match content {
+                        Some(content) => self.fs_op("write-file", &target, Some(content))?,
+                        None if self.list_dir(&target).is_err() => {
+                            self.fs_op("create-dir", &target, None)?
+                        }
+                        None => {}
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Validate every command, record pre-images of the paths they change, then
+    // apply them in order. The first failure restores all pre-images in
+    // reverse order and skips the remaining commands. Commands are numbered
+    // from `first`, their index in the message
+    fn process_transaction(
+        &self,
+        commands: Vec<FsCommand>,
+        first: usize,
+    ) -> (Vec<FsResult>, Transaction) {
+        let mut transaction = Transaction::staged();
+
+        // Stage: validate and capture pre-images before touching anything
+        let mut pre_images: Vec<(String, PreImage)> = Vec::new();
+        for (i, cmd) in commands.iter().enumerate() {
+            let staged = self.validate_command(cmd).and_then(|_| {
+                for path in self.affected_paths(cmd) {
+                    if !pre_images.iter().any(|(p, _)| *p == path) {
+                        let pre_image = self.snapshot(&path)?;
+                        pre_images.push((path, pre_image));
+                    }
+                }
+                Ok(())
+            });
+            if let Err(e) = staged {
+                let error = format!(
+                    "command {} ({} {}): {}",
+                    first + i + 1,
+                    cmd.operation,
+                    cmd.path,
+                    e
+                );
+                let results = commands
+                    .into_iter()
+                    .map(|cmd| {
+                        let error = format!("Transaction rejected: {}", error);
+                        FsResult::failure(cmd.operation, cmd.path, error)
+                    })
+                    .collect();
+                transaction.status = TransactionStatus::Rejected;
+                transaction.failed_command = Some(first + i);
+                transaction.error = Some(error);
+                return (results, transaction);
+            }
+        }
+
+        // Apply
+        let mut results = Vec::new();
+        let mut commands = commands.into_iter().enumerate();
+        for (i, cmd) in commands.by_ref() {
+            let result = self.execute_with_snapshots(cmd);
+            let failed = !result.success;
+            if failed {
+                transaction.failed_command = Some(first + i);
+                transaction.error = result.error.clone();
+            }
+            results.push(result);
+            if failed {
+                break;
+            }
+        }
+
+        if transaction.failed_command.is_none() {
+            transaction.status = TransactionStatus::Committed;
+            return (results, transaction);
+        }
+
+        // Roll back
+        for (path, pre_image) in pre_images.iter().rev() {
+            if let Err(e) = self.restore(path, pre_image) {
+                transaction
+                    .rollback_errors
+                    .push(format!("Failed to restore {}: {}", path, e));
+            }
+        }
+        for result in results.iter_mut().filter(|r| r.success) {
+            result.success = false;
+            result.error = Some("Rolled back".to_string());
+            result.snapshots = None;
+            result.hashes = None;
+        }
+        results.extend(commands.map(|(_, cmd)| {
+            let error = "Not applied, transaction rolled back".to_string();
+            FsResult::failure(cmd.operation, cmd.path, error)
+        }));
+        transaction.status = TransactionStatus::RolledBack;
+        (results, transaction)
+    }
+
+    // List a single directory through the fs-proxy. Entry kinds the proxy does
+    // not report are left empty, see `entry_kind`
+    fn list_dir(&self, path: &str) -> Result<Vec<FsEntry>, String> {
+        let resp = self.fs_request("list-files", path, None)?;
+        let mut entries = match (resp.success, resp.data) {
+            (true, Some(FsResponseData::FileList(files))) => files
+                .into_iter()
+                .map(|name| FsEntry {
+                    name,
+                    path: String::new(),
+                    kind: String::new(),
+                })
+                .collect(),
+            (true, Some(FsResponseData::Entries(entries))) => entries,
+            (true, _) => Vec::new(),
+            (false, _) => {
+                return Err(resp
+                    .error
+                    .unwrap_or_else(|| "Failed to list files".to_string()))
+            }
+        };
+
+        for entry in entries.iter_mut() {
+            entry.path = join_path(path, &entry.name);
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    // Directories are told apart from files by probing them with list-files
+    // when the proxy only returns names
+    fn entry_kind(&self, entry: &FsEntry) -> String {
+        match entry.kind.as_str() {
+            "file" | "dir" => entry.kind.clone(),
+            "directory" => "dir".to_string(),
+            _ if self.list_dir(&entry.path).is_ok() => "dir".to_string(),
+            _ => "file".to_string(),
+        }
+    }
+
+    // Walk the directory tree below `path` depth-first, keeping entries that
+    // match `pattern` and the directories that contain them
+    fn list_entries(
+        &self,
+        root: &str,
+        path: &str,
+        depth: usize,
+        pattern: Option<&Regex>,
+        budget: &mut usize,
+    ) -> Result<Vec<FsEntry>, String> {
+        let mut entries = Vec::new();
+        for mut entry in self.list_dir(path)? {
+            if *budget == 0 {
+                break;
+            }
+            entry.kind = self.entry_kind(&entry);
+
+            let matches = pattern.is_none_or(|re| glob_matches(re, root, &entry));
+            let descendants = if entry.kind == "dir" && depth > 1 {
+                self.list_entries(root, &entry.path, depth - 1, pattern, budget)?
+            } else {
+                Vec::new()
+            };
+
+            if matches || !descendants.is_empty() {
+                *budget = budget.saturating_sub(1);
+                entries.push(entry);
+                entries.extend(descendants);
+            }
+        }
+        Ok(entries)
+    }
+
+    fn list_files(&self, cmd: FsCommand) -> FsResult {
+        let depth = cmd.depth.unwrap_or(1).clamp(1, LIST_FILES_MAX_DEPTH);
+        let pattern = match cmd.pattern.as_deref().map(glob_to_regex).transpose() {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                return FsResult::failure(
+                    cmd.operation,
+                    cmd.path,
+                    format!("Invalid pattern: {}", e),
+                )
+            }
+        };
+
+        let mut budget = LIST_FILES_MAX_ENTRIES;
+        match self.list_entries(&cmd.path, &cmd.path, depth, pattern.as_ref(), &mut budget) {
+            Ok(entries) => {
+                let mut tree = render_entry_tree(&cmd.path, &entries);
+                if budget == 0 {
+                    tree.push_str(&format!(
+                        "\n[listing truncated at {} entries]",
+                        LIST_FILES_MAX_ENTRIES
+                    ));
+                }
+                FsResult {
+                    entries: Some(entries),
+                    ..FsResult::succeeded(cmd.operation, cmd.path, Some(tree))
+                }
+            }
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
+    }
+
+    // Run an operation through the fs-proxy that returns no data
+    fn fs_op(&self, operation: &str, path: &str, content: Option<&str>) -> Result<(), String> {
+        let resp = self.fs_request(operation, path, content)?;
+        if resp.success {
+            Ok(())
+        } else {
+            Err(resp
+                .error
+                .unwrap_or_else(|| format!("{} failed for {}", operation, path)))
+        }
+    }
+
+    fn path_exists(&self, path: &str) -> bool {
+        self.read_bytes(path).is_ok() || self.list_dir(path).is_ok()
+    }
+
+    // Copy a text file. The fs-proxy only writes strings, so binary files are
+    // refused rather than corrupted
+    fn copy_file(&self, source: &str, destination: &str) -> Result<(), String> {
+        let bytes = self.read_bytes(source)?;
+        if is_binary(&bytes) {
+            return Err(format!("{} is a binary file and cannot be copied", source));
+        }
+        let content = String::from_utf8(bytes).unwrap_or_default();
+        self.fs_op("write-file", destination, Some(&content))
+    }
+
+    fn copy_dir(&self, source: &str, destination: &str) -> Result<usize, String> {
+        let mut budget = LIST_FILES_MAX_ENTRIES;
+        let entries = self.list_entries(source, source, SEARCH_MAX_DEPTH, None, &mut budget)?;
+        if budget == 0 {
+            return Err(format!(
+                "{} has more than {} entries",
+                source, LIST_FILES_MAX_ENTRIES
+            ));
+        }
+
+        // Read every file first, so a binary file is refused before anything
+        // is written
+        let mut contents = HashMap::new();
+        for entry in entries.iter().filter(|entry| entry.kind != "dir") {
+            let bytes = self.read_bytes(&entry.path)?;
+            if is_binary(&bytes) {
+                return Err(format!(
+                    "{} is a binary file and cannot be copied",
+                    entry.path
+                ));
+            }
+            contents.insert(
+                entry.path.clone(),
+                String::from_utf8(bytes).unwrap_or_default(),
+            );
+        }
+
+        // Directories that already exist, when overwriting, are written into
+        if self.list_dir(destination).is_err() {
+            self.fs_op("create-dir", destination, None)?;
+        }
+        let mut copied = 0;
+        for entry in entries {
+            let target = join_path(destination, relative_to(source, &entry.path));
+            match contents.get(&entry.path) {
+                Some(content) => {
+                    self.fs_op("write-file", &target, Some(content))?;
+                    copied += 1;
+                }
+                None if self.list_dir(&target).is_err() => {
+                    self.fs_op("create-dir", &target, None)?;
+                }
+                None => {}
+            }
+        }
+        Ok(copied)
+    }
+
+    // The path a file ends up at: inside the destination when it is an existing
+    // directory
+    fn transfer_destination(&self, cmd: &FsCommand) -> Option<String> {
+        let destination = cmd.destination.clone()?;
+        if cmd.operation != "copy-dir" && self.list_dir(&destination).is_ok() {
+            let name = cmd
+                .path
+                .trim_end_matches('/')
+                .rsplit(['/', ':'])
+                .next()
+                .unwrap_or_default();
+            Some(join_path(&destination, name))
+        } else {
+            Some(destination)
+        }
+    }
+
+    // Check the source and destination of a transfer, returning the path the
+    // source ends up at
+    fn check_transfer(&self, cmd: &FsCommand) -> Result<String, String> {
+        let destination = cmd
+            .destination
+            .clone()
+            .ok_or_else(|| "Missing <destination>".to_string())?;
+        let source_is_dir = self.list_dir(&cmd.path).is_ok();
+        if cmd.operation != "copy-dir" && source_is_dir {
+            return Err(format!("{} is a directory, use copy-dir", cmd.path));
+        }
+        if cmd.operation == "copy-dir" && !source_is_dir {
+            return Err(format!("{} is not a directory", cmd.path));
+        }
+
+        let destination = self.transfer_destination(cmd).unwrap_or(destination);
+        let same_path = match (self.mount_for(&cmd.path), self.mount_for(&destination)) {
+            (Ok((a, source)), Ok((b, target))) => {
+                a.name == b.name && source.trim_end_matches('/') == target.trim_end_matches('/')
+            }
+            _ => false,
+        };
+        if same_path {
+            return Err("Source and destination are the same".to_string());
+        }
+        if !cmd.overwrite.unwrap_or(false) && self.path_exists(&destination) {
+            return Err(format!(
+                "{} already exists, set <overwrite>true</overwrite> to replace it",
+                destination
+            ));
+        }
+        Ok(destination)
+    }
+
+    // move-file, copy-file and copy-dir. A destination that is an existing
+    // directory receives the source under its own name
+    fn transfer(&self, cmd: FsCommand) -> FsResult {
+        let destination = match self.check_transfer(&cmd) {
+            Ok(destination) => destination,
+            Err(e) => return FsResult::failure(cmd.operation, cmd.path, e),
+        };
+
+        let outcome = match cmd.operation.as_str() {
+            "copy-dir" => self
+                .copy_dir(&cmd.path, &destination)
+                .map(|copied| format!("Copied {} files to {}", copied, destination)),
+            "copy-file" => self
+                .copy_file(&cmd.path, &destination)
+                .map(|_| format!("Copied to {}", destination)),
+            _ => self
+                .copy_file(&cmd.path, &destination)
+                .and_then(|_| self.fs_op("delete-file", &cmd.path, None))
+                .map(|_| format!("Moved to {}", destination)),
+        };
+        match outcome {
+            Ok(data) => FsResult::succeeded(cmd.operation, cmd.path, Some(data)),
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
+    }
+
+    // The actor's chain events, oldest first, keeping the last `limit` that are
+    // in one of `categories` (any when empty) and mention `path`
+    fn audit_log(
+        &self,
+        categories: &[String],
+        path: Option<&str>,
+        limit: usize,
+    ) -> Vec<AuditEntry> {
+        let entries: Vec<AuditEntry> = get_chain()
+            .events
+            .iter()
+            .map(|meta| audit_entry(meta, &self.api_key))
+            .filter(|e| categories.is_empty() || categories.contains(&e.category))
+            .filter(|e| path.is_none_or(|p| e.summary.contains(p)))
+            .collect();
+        let skip = entries.len().saturating_sub(limit);
+        entries.into_iter().skip(skip).collect()
+    }
+
+    // Delegate a task to a new filesystem-chat actor mounted on `path`, and
+    // keep it going until it replies without commands. `run_agent` then shuts
+    // it down and kills it, with its fs-proxies
+    fn spawn_agent(&self, cmd: FsCommand) -> FsResult {
+        match self.run_agent(&cmd) {
+            Ok(answer) => FsResult::succeeded(cmd.operation, cmd.path, Some(answer)),
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
+    }
+
+    fn run_agent(&self, cmd: &FsCommand) -> Result<String, String> {
+        let task = cmd.task.as_ref().ok_or("Missing <task>")?;
+        if self.agent_depth >= MAX_AGENT_DEPTH {
+            return Err(format!(
+                "Agents can't be nested more than {} levels deep",
+                MAX_AGENT_DEPTH
+            ));
+        }
+
+        let (mount, relative_path) = self.mount_for(&cmd.path)?;
+        let permissions = cmd
+            .permissions
+            .clone()
+            .unwrap_or_else(|| mount.permissions.clone());
+        if let Some(p) = permissions.iter().find(|p| !mount.permissions.contains(p)) {
+            return Err(format!(
+                "Permission '{}' is not granted on mount '{}'",
+                p, mount.name
+            ));
+        }
+        let agent_mount = Mount {
+            name: "root".to_string(),
+            path: self.resolve_path(mount, relative_path),
+            permissions,
+            backend: mount.backend,
+            ..Default::default()
+        };
+
+        let agent_id = spawn_agent_actor(self, &agent_mount)?;
+        log(&format!("Spawned agent {} for: {}", agent_id, task));
+
+        let result = drive_agent(&agent_id, task);
+
+        // The agent is done either way: let it stop its fs-proxies, then stop it
+        let req = json!({ "type": "shutdown" });
+        if let Err(e) = request(&agent_id, &serde_json::to_vec(&req).unwrap()) {
+            log(&format!("Failed to shut down agent {}: {}", agent_id, e));
+        }
+        kill(&agent_id);
+        result
+    }
+
+    // Stop the fs-proxies of an agent before it is killed
+    fn shutdown(&mut self) -> Result<Value, String> {
+        if self.agent_depth == 0 {
+            return Err("Only agents can be shut down".to_string());
+        }
+        for mount in self.mounts.iter_mut() {
+            if let Some(fs_proxy_id) = mount.fs_proxy_id.take() {
+                kill(&fs_proxy_id);
+            }
+            mount.status = ProxyStatus::Failed;
+        }
+        Ok(json!({ "shutdown": true }))
+    }
+
+    // Read a file through the fs-proxy as raw bytes
+    fn read_bytes(&self, path: &str) -> Result<Vec<u8>, String> {
+        let resp = self.fs_request("read-file", path, None)?;
+        match (resp.success, resp.data) {
+            (true, Some(FsResponseData::FileContent(content))) => Ok(content.into_bytes()),
+            (true, Some(FsResponseData::FileBytes(bytes))) => Ok(bytes),
+            (true, _) => Ok(Vec::new()),
+            (false, _) => Err(resp
+                .error
+                .unwrap_or_else(|| "Failed to read file".to_string())),
+        }
+    }
+
+    // All files below `path` (or `path` itself when it is a file) matching the
+    // optional glob
+    fn find_files(
+        &self,
+        path: &str,
+        depth: Option<usize>,
+        glob: Option<&str>,
+    ) -> Result<(Vec<FsEntry>, bool), String> {
+        let pattern = glob
+            .map(glob_to_regex)
+            .transpose()
+            .map_err(|e| format!("Invalid pattern: {}", e))?;
+
+        if let Err(list_error) = self.list_dir(path) {
+            // Not a directory: search the file itself, if there is one
+            if self.read_bytes(path).is_err() {
+                return Err(list_error);
+            }
+            let name = path.rsplit('/').next().unwrap_or(path).to_string();
+            let entry = FsEntry {
+                name,
+                path: path.to_string(),
+                kind: "file".to_string(),
+            };
+            return Ok((vec![entry], false));
+        }
+
+        let depth = depth.unwrap_or(SEARCH_MAX_DEPTH).clamp(1, SEARCH_MAX_DEPTH);
+        let mut budget = LIST_FILES_MAX_ENTRIES;
+        let files = self
+            .list_entries(path, path, depth, pattern.as_ref(), &mut budget)?
+            .into_iter()
+            .filter(|entry| entry.kind == "file")
+            .collect();
+        Ok((files, budget == 0))
+    }
+
+    fn search_files(&self, cmd: FsCommand) -> FsResult {
+        let Some(glob) = cmd.pattern.clone() else {
+            return FsResult::failure(cmd.operation, cmd.path, "Missing <pattern>".to_string());
+        };
+
+        match self.find_files(&cmd.path, cmd.depth, Some(&glob)) {
+            Ok((mut files, mut truncated)) => {
+                if files.len() > SEARCH_MAX_RESULTS {
+                    files.truncate(SEARCH_MAX_RESULTS);
+                    truncated = true;
+                }
+                let mut data = if files.is_empty() {
+                    format!("No files matching '{}'", glob)
+                } else {
+                    files
+                        .iter()
+                        .map(|f| f.path.as_str())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                if truncated {
+                    data.push_str("\n[results truncated]");
+                }
+                FsResult {
+                    entries: Some(files),
+                    ..FsResult::succeeded(cmd.operation, cmd.path, Some(data))
+                }
+            }
+            Err(e) => FsResult::failure(cmd.operation, cmd.path, e),
+        }
+    }
+
+    // Search file contents for a regex, reporting matches as `path:line: text`
+    // with context lines as `path-line- text`
+    fn grep(&self, cmd: FsCommand) -> FsResult {
+        let regex = match cmd.pattern.as_deref().map(Regex::new) {
+            Some(Ok(regex)) => regex,
+            Some(Err(e)) => {
+                return FsResult::failure(cmd.operation, cmd.path, format!("Invalid regex: {}", e))
+            }
+            None => {
+                return FsResult::failure(cmd.operation, cmd.path, "Missing <pattern>".to_string())
+            }
+        };
+        let context = cmd.context.unwrap_or(0).min(GREP_MAX_CONTEXT);
+
+        let (files, mut truncated) =
+            match self.find_files(&cmd.path, cmd.depth, cmd.include.as_deref()) {
+                Ok(found) => found,
+                Err(e) => return FsResult::failure(cmd.operation, cmd.path, e),
+            };
+
+        let mut output = Vec::new();
+        let mut match_count = 0;
+        for file in &files {
+            let Ok(bytes) = self.read_bytes(&file.path) else {
+                continue;
+            };
+            if is_binary(&bytes) {
+                continue;
+            }
+            let text = String::from_utf8_lossy(&bytes);
+            let lines: Vec<&str> = text.lines().collect();
+
+            let mut matches: Vec<usize> = lines
+                .iter()
+                .enumerate()
+                .filter(|(_, line)| regex.is_match(line))
+                .map(|(i, _)| i)
+                .collect();
+            if match_count + matches.len() > SEARCH_MAX_RESULTS {
+                matches.truncate(SEARCH_MAX_RESULTS - match_count);
+                truncated = true;
+            }
+            match_count += matches.len();
+
+            // Print each match with its context, merging overlapping ranges
+            let mut ranges: Vec<(usize, usize)> = Vec::new();
+            for &i in &matches {
+                let (start, end) = (
+                    i.saturating_sub(context),
+                    (i + context).min(lines.len() - 1),
+                );
+                match ranges.last_mut() {
+                    Some(last) if start <= last.1 + 1 => last.1 = end,
+                    _ => ranges.push((start, end)),
+                }
+            }
+            for (n, (start, end)) in ranges.into_iter().enumerate() {
+                if context > 0 && (n > 0 || !output.is_empty()) {
+                    output.push("--".to_string());
+                }
+                for (i, line) in lines.iter().enumerate().take(end + 1).skip(start) {
+                    let separator = if matches.contains(&i) { ':' } else { '-' };
+                    output.push(format!(
+                        "{}{}{}{} {}",
+                        file.path,
+                        separator,
+                        i + 1,
+                        separator,
+                        line
+                    ));
+                }
+            }
+
+            if truncated {
+                break;
+            }
+        }
+
+        let mut data = if output.is_empty() {
+            format!("No matches for '{}'", regex)
+        } else {
+            output.join("\n")
+        };
+        if truncated {
+            data.push_str("\n[results truncated]");
+        }
+        FsResult::succeeded(cmd.operation, cmd.path, Some(data))
+    }
+
+    // Build the nested tree served by /api/fs/tree
+    fn build_tree(
+        &self,
+        path: &str,
+        depth: usize,
+        budget: &mut usize,
+    ) -> Result<Vec<FsTreeNode>, String> {
+        let mut nodes = Vec::new();
+        for entry in self.list_dir(path)? {
+            if *budget == 0 {
+                break;
+            }
+            *budget -= 1;
+
+            let kind = self.entry_kind(&entry);
+            let children = match kind.as_str() {
+                "dir" if depth > 1 => Some(self.build_tree(&entry.path, depth - 1, budget)?),
+                "dir" => Some(Vec::new()),
+                _ => None,
+            };
+            nodes.push(FsTreeNode {
+                name: entry.name,
+                path: entry.path,
+                kind,
+                children,
+            });
+        }
+        Ok(nodes)
+    }
+
+    // One directory node per mount, empty for mounts that can't be listed
+    fn mounts_tree(&self, depth: usize, budget: &mut usize) -> Vec<FsTreeNode> {
+        self.mounts
+            .iter()
+            .map(|mount| {
+                let root = format!("{}:", mount.name);
+                let children = if self.allowed_operation("list-files", &root) {
+                    self.build_tree(&root, depth, budget).unwrap_or_default()
+                } else {
+                    Vec::new()
+                };
+                FsTreeNode {
+                    name: mount.name.clone(),
+                    path: root,
+                    kind: "dir".to_string(),
+                    children: Some(children),
+                }
+            })
+            .collect()
+    }
+}
+
+impl ActorGuest for Component {
+    fn init(data: Option<Json>) -> Json {
+        log("Initializing filesystem chat actor");
+        let data = data.unwrap();
+
+        let init_data: InitData = serde_json::from_slice(&data).unwrap();
+        log(&format!("Store actor id: {}", init_data.store_id));
+        log(&format!("Websocket port: {}", init_data.websocket_port));
+
+        // Without mounts, fs_path and permissions make up a single one
+        let mut mounts = init_data.mounts;
+        if mounts.is_empty() {
+            mounts.push(Mount {
+                name: "root".to_string(),
+                path: init_data.fs_path,
+                permissions: init_data.permissions,
+                backend: init_data.backend,
+                ..Default::default()
+            });
+        }
+
+        // Spawn an fs-proxy actor for each mount
+        for i in 0..mounts.len() {
+            let name = &mounts[i].name;
+            let valid = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid || mounts[..i].iter().any(|m| &m.name == name) {
+                log(&format!("Invalid or duplicate mount name: {:?}", name));
+                return vec![];
+            }
+            log(&format!(
+                "Mount {}: {} {:?} ({:?})",
+                name, mounts[i].path, mounts[i].permissions, mounts[i].backend
+            ));
+            if mounts[i].backend == Backend::Builtin {
+                if let Err(e) = check_builtin_mount(&mounts[i].path) {
+                    log(&format!("Invalid builtin mount '{}': {}", name, e));
+                    return vec![];
+                }
+                mounts[i].status = ProxyStatus::Running;
+                continue;
+            }
+
+            match spawn_fs_proxy(&mounts[i]) {
+                Ok(fs_proxy_id) => {
+                    log(&format!("Spawned fs-proxy actor: {}", fs_proxy_id));
+                    mounts[i].fs_proxy_id = Some(fs_proxy_id);
+                }
+                Err(e) => {
+                    log(&e);
+                    return vec![];
+                }
+            }
+        }
+
+        // Read API key
+        log("Reading API key");
+        let res = read_file("api-key.txt");
+        if res.is_err() {
+            log("Failed to read API key");
+            return vec![];
+        }
+        let api_key = res.unwrap();
+        log("API key read");
+        let api_key = String::from_utf8(api_key).unwrap().trim().to_string();
+        log("API key loaded");
+
+        let initial_state = State {
+            store_id: init_data.store_id,
+            mounts,
+            head: None,
+            conversation_id: None,
+            conversations: Vec::new(),
+            conversations_started: 0,
+            children: HashMap::new(),
+            dry_run: false,
+            subscribers: Vec::new(),
+            audit_head: None,
+            agent_depth: init_data.agent_depth,
+            websocket_port: init_data.websocket_port,
+            api_key,
+        };
+
+        serde_json::to_vec(&initial_state).unwrap()
+    }
+}
+
+impl HttpGuest for Component {
+    fn handle_request(request: HttpRequest, state: Json) -> (HttpResponse, Json) {
+        let mut state: State = serde_json::from_slice(&state).unwrap();
+        state.supervise();
+        let (segments, query) = split_uri(&request.uri);
+
+        let response = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", []) | ("GET", ["index.html"]) => match read_file("index.html") {
+                Ok(content) => HttpResponse {
+                    status: 200,
+                    headers: vec![("Content-Type".to_string(), "text/html".to_string())],
+                    body: Some(content),
+                },
+                Err(e) => HttpResponse {
+                    status: 500,
+                    headers: vec![],
+                    body: Some(format!("Failed to read index.html: {}", e).into_bytes()),
+                },
+            },
+            ("GET", ["styles.css"]) => match read_file("styles.css") {
+                Ok(content) => HttpResponse {
+                    status: 200,
+                    headers: vec![("Content-Type".to_string(), "text/css".to_string())],
+                    body: Some(content),
+                },
+                Err(e) => HttpResponse {
+                    status: 500,
+                    headers: vec![],
+                    body: Some(format!("Failed to read styles.css: {}", e).into_bytes()),
+                },
+            },
+            ("GET", ["chat.js"]) => match read_file("chat.js") {
+                Ok(content) => {
+                    let str_content = String::from_utf8(content).unwrap();
+                    let content = str_content
+                        .replace("{{WEBSOCKET_PORT}}", &format!("{}", state.websocket_port));
+                    HttpResponse {
+                        status: 200,
+                        headers: vec![(
+                            "Content-Type".to_string(),
+                            "application/javascript".to_string(),
+                        )],
+                        body: Some(content.into_bytes()),
+                    }
+                }
+                Err(e) => HttpResponse {
+                    status: 500,
+                    headers: vec![],
+                    body: Some(format!("Failed to read chat.js: {}", e).into_bytes()),
+                },
+            },
+            ("GET", ["api", "messages"]) => match state.get_message_history() {
+                Ok(messages) => HttpResponse {
+                    status: 200,
+                    headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+                    body: Some(
+                        serde_json::to_vec(&json!({
+                            "type": "message_update",
+                            "messages": messages
+                        }))
+                        .unwrap(),
+                    ),
+                },
+                Err(e) => HttpResponse {
+                    status: 500,
+                    headers: vec![],
+                    body: Some(format!("Failed to load messages: {}", e).into_bytes()),
+                },
+            },
+            ("POST", ["api", "messages"]) => match parse_body(&request) {
+                Ok(body) => match body["content"].as_str() {
+                    Some(content) => {
+                        let fs_commands = parse_command_list(&body["fs_commands"]);
+                        let attachments = state.collect_attachments(&body);
+                        let transaction = body["transaction"].as_bool().unwrap_or(false);
+                        match state.send_user_message(
+                            content.to_string(),
+                            fs_commands,
+                            attachments,
+                            transaction,
+                        ) {
+                            Ok(message_state) => message_state_response(&state, message_state),
+                            Err(e) => error_response(500, &e),
+                        }
+                    }
+                    None => error_response(400, "Missing 'content' field"),
+                },
+                Err(response) => response,
+            },
+            ("POST", ["api", "messages", id, "retry"]) => match state.retry_message(id) {
+                Ok(message_state) => message_state_response(&state, message_state),
+                Err(e) => error_response(404, &e),
+            },
+            ("POST", ["api", "messages", id, "undo"]) => match state.undo_message(id) {
+                Ok(results) => json_response(200, json!({ "message_id": id, "results": results })),
+                Err(e) => error_response(404, &e),
+            },
+            ("GET", ["api", "messages", id]) => match state.load_message(id) {
+                Ok(message) => json_response(200, json!({ "message": message })),
+                Err(e) => error_response(404, &format!("Message {} not found: {}", id, e)),
+            },
+            ("GET", ["api", "messages", id, "children"]) => match state.load_message(id) {
+                Ok(_) => match state.get_children(id) {
+                    Ok(messages) => json_response(200, json!({ "messages": messages })),
+                    Err(e) => error_response(500, &format!("Failed to load children: {}", e)),
+                },
+                Err(e) => error_response(404, &format!("Message {} not found: {}", id, e)),
+            },
+            ("GET", ["api", "fs", "list"]) => {
+                let path = query_param(query, "path").unwrap_or_else(|| ".".to_string());
+                if !state.allowed_operation("list-files", &path) {
+                    error_response(403, &state.permission_error("list-files", &path))
+                } else {
+                    let result = state.execute_fs_command(FsCommand {
+                        depth: query_param(query, "depth").and_then(|d| d.parse().ok()),
+                        pattern: query_param(query, "pattern"),
+                        ..FsCommand::new("list-files", &path)
+                    });
+                    if result.success {
+                        json_response(200, json!({ "path": path, "entries": result.entries }))
+                    } else {
+                        error_response(404, &result.error.unwrap_or_default())
+                    }
+                }
+            }
+            ("GET", ["api", "fs", "read"]) => match query_param(query, "path") {
+                Some(path) if !state.allowed_operation("read-file", &path) => {
+                    error_response(403, &state.permission_error("read-file", &path))
+                }
+                Some(path) => {
+                    let result = state.execute_fs_command(FsCommand {
+                        offset: query_param(query, "offset").and_then(|o| o.parse().ok()),
+                        limit: query_param(query, "limit").and_then(|l| l.parse().ok()),
+                        unit: query_param(query, "unit"),
+                        ..FsCommand::new("read-file", &path)
+                    });
+                    if result.success {
+                        json_response(
+                            200,
+                            json!({
+                                "path": path,
+                                "content": result.data.unwrap_or_default(),
+                                "media_type": result.media_type,
+                            }),
+                        )
+                    } else {
+                        error_response(404, &result.error.unwrap_or_default())
+                    }
+                }
+                None => error_response(400, "Missing 'path' parameter"),
+            },
+            ("GET", ["api", "fs", "tree"]) => {
+                let path = query_param(query, "path");
+                let depth = query_param(query, "depth")
+                    .and_then(|d| d.parse().ok())
+                    .unwrap_or(FS_TREE_DEFAULT_DEPTH)
+                    .clamp(1, FS_TREE_MAX_DEPTH);
+                if path.is_none() && state.mounts.len() > 1 {
+                    // With several mounts the tree starts with one node per mount
+                    let mut budget = FS_TREE_MAX_ENTRIES;
+                    let tree = state.mounts_tree(depth, &mut budget);
+                    json_response(
+                        200,
+                        json!({ "path": ".", "tree": tree, "truncated": budget == 0 }),
+                    )
+                } else if !state.allowed_operation("list-files", path.as_deref().unwrap_or(".")) {
+                    let path = path.unwrap_or_else(|| ".".to_string());
+                    error_response(403, &state.permission_error("list-files", &path))
+                } else {
+                    let path = path.unwrap_or_else(|| ".".to_string());
+                    let mut budget = FS_TREE_MAX_ENTRIES;
+                    match state.build_tree(&path, depth, &mut budget) {
+                        Ok(tree) => json_response(
+                            200,
+                            json!({ "path": path, "tree": tree, "truncated": budget == 0 }),
+                        ),
+                        Err(e) => error_response(404, &e),
+                    }
+                }
+            }
+            ("GET", ["api", "mounts"]) => {
+                state.health_check();
+                let mounts: Vec<Value> = state
+                    .mounts
+                    .iter()
+                    .map(|m| {
+                        json!({
+                            "name": m.name,
+                            "path": m.path,
+                            "permissions": m.permissions,
+                            "backend": m.backend,
+                            "available": m.backend == Backend::Builtin || m.fs_proxy_id.is_some(),
+                            "status": m.status,
+                            "restarts": m.restarts,
+                        })
+                    })
+                    .collect();
+                json_response(200, json!({ "mounts": mounts }))
+            }
+            ("POST", ["api", "mounts", name, "restart"]) => match state.restart_mount(name) {
+                Ok(()) => json_response(200, json!({ "restarted": name })),
+                Err(e) if e.starts_with("Unknown") => error_response(404, &e),
+                Err(e) => error_response(409, &e),
+            },
+            ("GET", ["api", "audit"]) => {
+                let categories: Vec<String> = query_param(query, "category")
+                    .map(|c| c.split(',').map(String::from).collect())
+                    .unwrap_or_default();
+                let limit = query_param(query, "limit")
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(AUDIT_DEFAULT_LIMIT)
+                    .min(AUDIT_MAX_LIMIT);
+                let path = query_param(query, "path");
+                let events = state.audit_log(&categories, path.as_deref(), limit);
+                json_response(200, json!({ "events": events }))
+            }
+            ("GET", ["api", "audit", "records"]) => {
+                let limit = query_param(query, "limit")
+                    .and_then(|l| l.parse().ok())
+                    .unwrap_or(AUDIT_DEFAULT_LIMIT)
+                    .min(AUDIT_MAX_LIMIT);
+                match state.audit_records(limit) {
+                    Ok(records) => json_response(200, json!({ "records": records })),
+                    Err(e) => error_response(500, &format!("Failed to load audit records: {}", e)),
+                }
+            }
+            ("GET", ["api", "audit", "verify"]) => {
+                let head = state.audit_head.as_ref().map(|h| h.hash.clone());
+                match state.verify_audit() {
+                    Ok(records) => json_response(
+                        200,
+                        json!({ "valid": true, "records": records, "head": head }),
+                    ),
+                    Err(e) => {
+                        json_response(200, json!({ "valid": false, "error": e, "head": head }))
+                    }
+                }
+            }
+            ("GET", ["api", "export"]) => {
+                let format = query_param(query, "format").unwrap_or_else(|| "markdown".to_string());
+                let conversation = query_param(query, "conversation");
+                match state.export_conversation(conversation.as_deref(), &format) {
+                    Ok(body) => {
+                        let (content_type, extension) = match format.as_str() {
+                            "markdown" => ("text/markdown", "md"),
+                            "jsonl" => ("application/x-ndjson", "jsonl"),
+                            _ => ("application/json", "json"),
+                        };
+                        HttpResponse {
+                            status: 200,
+                            headers: vec![
+                                ("Content-Type".to_string(), content_type.to_string()),
+                                (
+                                    "Content-Disposition".to_string(),
+                                    format!("attachment; filename=\"conversation.{}\"", extension),
+                                ),
+                            ],
+                            body: Some(body.into_bytes()),
+                        }
+                    }
+                    Err(e) if e.to_string().starts_with("Unknown") => {
+                        error_response(400, &e.to_string())
+                    }
+                    Err(e) if e.to_string().ends_with("not found") => {
+                        error_response(404, &e.to_string())
+                    }
+                    Err(e) => error_response(500, &format!("Failed to export: {}", e)),
+                }
+            }
+            ("POST", ["api", "import"]) => match parse_body(&request) {
+                Ok(body) => match state.import_conversation(&body) {
+                    Ok(imported) => json_response(201, imported),
+                    Err(e) => error_response(400, &e),
+                },
+                Err(response) => response,
+            },
+            ("GET", ["api", "search"]) => {
+                let q = query_param(query, "q").unwrap_or_default();
+                match state.search_messages(&q) {
+                    Ok(results) => json_response(200, json!({ "query": q, "results": results })),
+                    Err(e) => error_response(400, &e),
+                }
+            }
+            ("GET", ["api", "changes"]) => match state.change_log() {
+                Ok(changes) => json_response(200, json!({ "changes": changes })),
+                Err(e) => error_response(500, &format!("Failed to build change log: {}", e)),
+            },
+            ("GET", ["api", "dry-run"]) => json_response(200, json!({ "enabled": state.dry_run })),
+            ("PUT", ["api", "dry-run"]) => match parse_body(&request) {
+                Ok(body) => match body["enabled"].as_bool() {
+                    Some(enabled) => {
+                        state.set_dry_run(enabled);
+                        json_response(200, json!({ "enabled": enabled }))
+                    }
+                    None => error_response(400, "Missing boolean field: enabled"),
+                },
+                Err(response) => response,
+            },
+            ("GET", ["api", "conversations"]) => json_response(
+                200,
+                json!({
+                    "current": state.conversation_id,
+                    "conversations": state.conversations,
+                }),
+            ),
+            ("DELETE", ["api", "conversations", id]) => {
+                if state.delete_conversation(id) {
+                    json_response(200, json!({ "deleted": id }))
+                } else {
+                    error_response(404, &format!("Conversation {} not found", id))
+                }
+            }
+            _ => HttpResponse {
+                status: 404,
+                headers: vec![],
+                body: Some(b"Not Found".to_vec()),
+            },
+        };
+
+        (response, serde_json::to_vec(&state).unwrap())
+    }
+}
+
+impl WebSocketGuest for Component {
+    fn handle_message(message: WebsocketMessage, state: Json) -> (Json, WebsocketResponse) {
+        log(&format!("Received message: {:?}", message));
+        let mut state: State = serde_json::from_slice(&state).unwrap();
+        state.supervise();
+
+        match message.ty {
+            MessageType::Text => {
+                if let Some(text) = message.text {
+                    if let Ok(command) = serde_json::from_str::<Value>(&text) {
+                        match command["type"].as_str() {
+                            Some("send_message") => {
+                                log("Matched send_message command");
+                                if let Some(content) = command["content"].as_str() {
+                                    log(&format!("Processing content: {}", content));
+
+                                    // Extract filesystem commands if present
+                                    let fs_commands = parse_command_list(&command["fs_commands"]);
+                                    let attachments = state.collect_attachments(&command);
+
+                                    let transaction =
+                                        command["transaction"].as_bool().unwrap_or(false);
+
+                                    if let Ok(message_state) = state.send_user_message(
+                                        content.to_string(),
+                                        fs_commands,
+                                        attachments,
+                                        transaction,
+                                    ) {
+                                        return send_message_state_update(state, message_state);
+                                    }
+                                }
+                            }
+                            Some("retry_message") => {
+                                if let Some(message_id) = command["messageId"].as_str() {
+                                    if let Ok(message_state) = state.retry_message(message_id) {
+                                        return send_message_state_update(state, message_state);
+                                    }
+                                }
+                            }
+                            Some("undo_message") => {
+                                if let Some(message_id) = command["messageId"].as_str() {
+                                    let response = match state.undo_message(message_id) {
+                                        Ok(results) => json!({
+                                            "type": "undo_result",
+                                            "message_id": message_id,
+                                            "results": results,
+                                        }),
+                                        Err(error) => json!({
+                                            "type": "undo_result",
+                                            "message_id": message_id,
+                                            "error": error,
+                                        }),
+                                    };
+                                    return (
+                                        serde_json::to_vec(&state).unwrap(),
+                                        WebsocketResponse {
+                                            messages: vec![WebsocketMessage {
+                                                ty: MessageType::Text,
+                                                text: Some(response.to_string()),
+                                                data: None,
+                                            }],
+                                        },
+                                    );
+                                }
+                            }
+                            Some("set_dry_run") => {
+                                if let Some(enabled) = command["enabled"].as_bool() {
+                                    state.set_dry_run(enabled);
+                                    return (
+                                        serde_json::to_vec(&state).unwrap(),
+                                        WebsocketResponse {
+                                            messages: vec![WebsocketMessage {
+                                                ty: MessageType::Text,
+                                                text: Some(
+                                                    json!({ "type": "dry_run", "enabled": enabled })
+                                                        .to_string(),
+                                                ),
+                                                data: None,
+                                            }],
+                                        },
+                                    );
+                                }
+                            }
+                            Some("get_audit") => {
+                                let categories: Vec<String> = command["category"]
+                                    .as_str()
+                                    .map(|c| c.split(',').map(String::from).collect())
+                                    .unwrap_or_default();
+                                let limit = command["limit"]
+                                    .as_u64()
+                                    .map_or(AUDIT_DEFAULT_LIMIT, |l| l as usize)
+                                    .min(AUDIT_MAX_LIMIT);
+                                let events =
+                                    state.audit_log(&categories, command["path"].as_str(), limit);
+                                return (
+                                    serde_json::to_vec(&state).unwrap(),
+                                    WebsocketResponse {
+                                        messages: vec![WebsocketMessage {
+                                            ty: MessageType::Text,
+                                            text: Some(
+                                                json!({ "type": "audit", "events": events })
+                                                    .to_string(),
+                                            ),
+                                            data: None,
+                                        }],
+                                    },
+                                );
+                            }
+                            Some("search") => {
+                                let q = command["q"].as_str().unwrap_or_default();
+                                let response = match state.search_messages(q) {
+                                    Ok(results) => {
+                                        json!({ "type": "search_results", "query": q, "results": results })
+                                    }
+                                    Err(e) => {
+                                        json!({ "type": "search_results", "query": q, "error": e })
+                                    }
+                                };
+                                return (
+                                    serde_json::to_vec(&state).unwrap(),
+                                    WebsocketResponse {
+                                        messages: vec![WebsocketMessage {
+                                            ty: MessageType::Text,
+                                            text: Some(response.to_string()),
+                                            data: None,
+                                        }],
+                                    },
+                                );
+                            }
+                            Some("get_changes") => {
+                                let response = match state.change_log() {
+                                    Ok(changes) => json!({ "type": "changes", "changes": changes }),
+                                    Err(e) => json!({ "type": "changes", "error": e.to_string() }),
+                                };
+                                return (
+                                    serde_json::to_vec(&state).unwrap(),
+                                    WebsocketResponse {
+                                        messages: vec![WebsocketMessage {
+                                            ty: MessageType::Text,
+                                            text: Some(response.to_string()),
+                                            data: None,
+                                        }],
+                                    },
+                                );
+                            }
+                            Some("get_messages") => {
+                                if let Ok(messages) = state.get_message_history() {
+                                    return (
+                                        serde_json::to_vec(&state).unwrap(),
+                                        WebsocketResponse {
+                                            messages: vec![WebsocketMessage {
+                                                ty: MessageType::Text,
+                                                text: Some(
+                                                    json!({
+                                                        "type": "message_update",
+                                                        "messages": messages
+                                                    })
+                                                    .to_string(),
+                                                ),
+                                                data: None,
+                                            }],
+                                        },
+                                    );
+                                }
+                            }
+                            _ => {
+                                log("Unknown command type received");
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        (
+            serde_json::to_vec(&state).unwrap(),
+            WebsocketResponse { messages: vec![] },
+        )
+    }
+}
 
 impl MessageServerClient for Component {
     fn handle_send(message: Json, state: Json) -> Json {
         let mut state: State = serde_json::from_slice(&state).unwrap();
 
-        // Attempt to parse as WasmEvent
-        if let Ok(event) = serde_json::from_slice::<WasmEvent>(&message) {
-            match event.type_.as_str() {
-                "init" => {
-                    // Handle initialization of fs-proxy
-                    if let Ok(data) = String::from_utf8(event.data) {
-                        log(&format!("FS-Proxy initialized: {}", data));
-                    }
-                }
-                "terminate" => {
-                    // Handle fs-proxy termination
-                    if let Some(fs_proxy_id) = &state.fs_proxy_id {
-                        log(&format!("FS-Proxy terminated: {}", fs_proxy_id));
-                        state.fs_proxy_id = None;
+        // Attempt to parse as WasmEvent
+        if let Ok(event) = serde_json::from_slice::<WasmEvent>(&message) {
+            match event.type_.as_str() {
+                "init" => {
+                    // Handle initialization of fs-proxy
+                    if let Ok(data) = String::from_utf8(event.data) {
+                        log(&format!("FS-Proxy initialized: {}", data));
+                    }
+                }
+                "terminate" => {
+                    // Handle fs-proxy termination. Agents are children too, so
+                    // only the mount whose proxy the event names is affected
+                    let actor_id = String::from_utf8(event.data).unwrap_or_default();
+                    for mount in state.mounts.iter_mut() {
+                        if mount.fs_proxy_id.as_ref() == Some(&actor_id) {
+                            mount.terminated();
+                        }
+                    }
+                }
+                _ => {
+                    log(&format!("Received unknown event type: {}", event.type_));
+                }
+            }
+        }
+        state.supervise();
+
+        serde_json::to_vec(&state).unwrap()
+    }
+
+    fn handle_request(message: Json, state: Json) -> (Json, Json) {
+        let mut state: State = serde_json::from_slice(&state).unwrap();
+        state.supervise();
+
+        // Requests from other actors are JSON objects with a `type`, answered
+        // with `success` and the result fields, or an `error`
+        let request: Value = serde_json::from_slice(&message).unwrap_or(Value::Null);
+        log(&format!("Received actor request: {}", request["type"]));
+        let result = match request["type"].as_str() {
+            Some("send_message") => match request["content"].as_str() {
+                Some(content) => {
+                    let fs_commands = parse_command_list(&request["fs_commands"]);
+                    let attachments = state.collect_attachments(&request);
+                    let transaction = request["transaction"].as_bool().unwrap_or(false);
+                    state
+                        .send_user_message(
+                            content.to_string(),
+                            fs_commands,
+                            attachments,
+                            transaction,
+                        )
+                        .map(|message_state| actor_reply(&state, &message_state))
+                }
+                None => Err("Missing 'content' field".to_string()),
+            },
+            Some("retry_message") => match request["message_id"].as_str() {
+                Some(id) => state
+                    .retry_message(id)
+                    .map(|message_state| actor_reply(&state, &message_state)),
+                None => Err("Missing 'message_id' field".to_string()),
+            },
+            Some("get_messages") => state
+                .get_message_history()
+                .map(|messages| json!({ "head": state.head, "messages": messages }))
+                .map_err(|e| format!("Failed to load messages: {}", e)),
+            Some("get_message") => match request["message_id"].as_str() {
+                Some(id) => state
+                    .load_message(id)
+                    .map(|message| json!({ "message": message }))
+                    .map_err(|e| format!("Message {} not found: {}", id, e)),
+                None => Err("Missing 'message_id' field".to_string()),
+            },
+            Some("list_conversations") => Ok(json!({
+                "current": state.conversation_id,
+                "conversations": state.conversations,
+            })),
+            Some("switch_conversation") => match request["conversation_id"].as_str() {
+                Some(id) => state
+                    .switch_conversation(id)
+                    .map(|_| json!({ "current": id, "head": state.head })),
+                None => Err("Missing 'conversation_id' field".to_string()),
+            },
+            Some("new_conversation") => {
+                state.new_conversation();
+                Ok(json!({ "current": null }))
+            }
+            Some("subscribe") => match request["actor_id"].as_str() {
+                Some(actor_id) => {
+                    let events = request["events"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|e| e.as_str().map(String::from))
+                        .collect();
+                    state
+                        .subscribe(actor_id, events)
+                        .map(|_| json!({ "subscribed": actor_id }))
+                }
+                None => Err("Missing 'actor_id' field".to_string()),
+            },
+            Some("shutdown") => state.shutdown(),
+            Some("unsubscribe") => match request["actor_id"].as_str() {
+                Some(actor_id) if state.unsubscribe(actor_id) => {
+                    Ok(json!({ "unsubscribed": actor_id }))
+                }
+                Some(actor_id) => Err(format!("{} is not subscribed", actor_id)),
+                None => Err("Missing 'actor_id' field".to_string()),
+            },
+            Some(other) => Err(format!("Unknown request type: {}", other)),
+            None => Err("Missing 'type' field".to_string()),
+        };
+
+        let response = match result {
+            Ok(mut body) => {
+                if body.get("success").is_none() {
+                    body["success"] = json!(true);
+                }
+                body
+            }
+            Err(error) => json!({ "success": false, "error": error }),
+        };
+
+        (
+            serde_json::to_vec(&response).unwrap(),
+            serde_json::to_vec(&state).unwrap(),
+        )
+    }
+}
+
+// Helper function to detect the media type of files the model can view
+fn media_type_for(path: &str, bytes: &[u8]) -> Option<&'static str> {
+    let by_magic = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else {
+        None
+    };
+
+    by_magic.or_else(|| {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "png" => Some("image/png"),
+            "jpg" | "jpeg" => Some("image/jpeg"),
+            "gif" => Some("image/gif"),
+            "webp" => Some("image/webp"),
+            "pdf" => Some("application/pdf"),
+            _ => None,
+        }
+    })
+}
+
+// Helper function to turn the bytes of a read file into FsResult data: text is
+// returned as is, images and PDFs base64 encoded with their media type, and
+// other binary files replaced by a notice
+fn file_content_data(path: &str, bytes: Vec<u8>) -> (Option<String>, Option<String>) {
+    if !is_binary(&bytes) {
+        return (Some(String::from_utf8(bytes).unwrap_or_default()), None);
+    }
+
+    match media_type_for(path, &bytes) {
+        Some(_) if bytes.len() > MAX_MEDIA_BYTES => (
+            Some(format!(
+                "[binary file, {} bytes, larger than the {} byte limit]",
+                bytes.len(),
+                MAX_MEDIA_BYTES
+            )),
+            None,
+        ),
+        Some(media_type) => (Some(BASE64.encode(&bytes)), Some(media_type.to_string())),
+        None => (Some(format!("[binary file, {} bytes]", bytes.len())), None),
+    }
+}
+
+fn count_lines(bytes: &[u8]) -> usize {
+    let newlines = bytes.iter().filter(|b| **b == b'\n').count();
+    if bytes.last().is_some_and(|b| *b != b'\n') {
+        newlines + 1
+    } else {
+        newlines
+    }
+}
+
+// Helper function to select the requested lines of a text file. Output past
+// READ_MAX_BYTES is cut at a line boundary with a notice saying how to continue,
+// or within the first line when even that doesn't fit
+fn read_lines(text: &str, cmd: &FsCommand) -> String {
+    let ranged = cmd.offset.is_some() || cmd.limit.is_some();
+    let numbered = cmd.line_numbers.unwrap_or(ranged);
+    if !ranged && !numbered && text.len() <= READ_MAX_BYTES {
+        return text.to_string();
+    }
+
+    let lines: Vec<&str> = text.lines().collect();
+    let offset = cmd.offset.unwrap_or(0).min(lines.len());
+    let end = cmd.limit.map_or(lines.len(), |limit| {
+        offset.saturating_add(limit).min(lines.len())
+    });
+    let width = end.to_string().len();
+
+    let mut output = String::new();
+    let mut shown_until = offset;
+    for (i, line) in lines.iter().enumerate().take(end).skip(offset) {
+        let prefix = if numbered {
+            format!("{:>width$}\t", i + 1, width = width)
+        } else {
+            String::new()
+        };
+        if output.len() + prefix.len() + line.len() + 1 > READ_MAX_BYTES {
+            if output.is_empty() {
+                // Show what fits of an overlong line and continue by bytes
+                let mut cut = READ_MAX_BYTES.saturating_sub(prefix.len() + 1);
+                while !line.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                let line_start = line.as_ptr() as usize - text.as_ptr() as usize;
+                return format!(
+                    "{}{}\n[truncated: line {} is longer than {} bytes, use <offset>{}</offset><unit>bytes</unit> to read more]",
+                    prefix,
+                    &line[..cut],
+                    i + 1,
+                    READ_MAX_BYTES,
+                    line_start + cut
+                );
+            }
+            break;
+        }
+        output.push_str(&prefix);
+        output.push_str(line);
+        output.push('\n');
+        shown_until = i + 1;
+    }
+
+    if shown_until < end {
+        output.push_str(&format!(
+            "[truncated: showing lines {}-{} of {}, use <offset>{}</offset> to read more]",
+            offset + 1,
+            shown_until,
+            lines.len(),
+            shown_until
+        ));
+    } else if ranged {
+        output.push_str(&format!(
+            "[lines {}-{} of {}]",
+            offset + 1,
+            shown_until,
+            lines.len()
+        ));
+    }
+    output
+}
+
+// Helper function to select a byte range of a text file, widened to the
+// nearest character boundaries
+fn read_bytes_range(text: &str, cmd: &FsCommand) -> String {
+    let mut start = cmd.offset.unwrap_or(0).min(text.len());
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let limit = cmd.limit.unwrap_or(READ_MAX_BYTES).min(READ_MAX_BYTES);
+    let mut end = start.saturating_add(limit).min(text.len());
+    while !text.is_char_boundary(end) {
+        end += 1;
+    }
+
+    let mut output = text[start..end].to_string();
+    if start > 0 || end < text.len() {
+        output.push_str(&format!("\n[bytes {}-{} of {}]", start, end, text.len()));
+    }
+    output
+}
+
+// Helper function to render a unified diff of a file, `None` meaning the file
+// does not exist
+fn unified_diff(path: &str, old: Option<&str>, new: Option<&str>) -> String {
+    let old_header = old.map_or("/dev/null".to_string(), |_| format!("a/{}", path));
+    let new_header = new.map_or("/dev/null".to_string(), |_| format!("b/{}", path));
+    TextDiff::from_lines(old.unwrap_or_default(), new.unwrap_or_default())
+        .unified_diff()
+        .context_radius(3)
+        .header(&old_header, &new_header)
+        .to_string()
+}
+
+// Helper function to run an fs-proxy operation in-actor on the filesystem host
+// import, answering the way the fs-proxy does
+fn builtin_fs_request(operation: &str, path: &str, content: Option<&str>) -> FsResponse {
+    let outcome = match operation {
+        "read-file" => read_file(path).map(|bytes| Some(FsResponseData::FileBytes(bytes))),
+        "list-files" => list_files(path).map(|files| Some(FsResponseData::FileList(files))),
+        "write-file" => write_file(path, content.unwrap_or_default()).map(|_| None),
+        "delete-file" => delete_file(path).map(|_| None),
+        "create-dir" => create_dir(path).map(|_| None),
+        "delete-dir" => delete_dir(path).map(|_| None),
+        _ => Err(format!("Unsupported operation: {}", operation)),
+    };
+    match outcome {
+        Ok(data) => FsResponse {
+            success: true,
+            data,
+            error: None,
+        },
+        Err(e) => FsResponse {
+            success: false,
+            data: None,
+            error: Some(e),
+        },
+    }
+}
+
+// Helper function to check that a builtin mount is a dedicated subdirectory of
+// the actor's own files, away from its API key, served assets and the
+// manifests it writes for the actors it spawns
+fn check_builtin_mount(path: &str) -> Result<(), String> {
+    let path = path.trim_end_matches('/');
+    if path.starts_with('/') {
+        return Err("the path must be relative to the actor's filesystem".to_string());
+    }
+    let components: Vec<&str> = path.split('/').collect();
+    if components
+        .iter()
+        .any(|c| c.is_empty() || *c == "." || *c == "..")
+    {
+        return Err(format!("'{}' is not a subdirectory", path));
+    }
+    if BUILTIN_RESERVED_PATHS.contains(&components[0]) {
+        return Err(format!("'{}' holds the actor's own files", components[0]));
+    }
+    Ok(())
+}
+
+// Helper function to spawn a filesystem-chat actor for spawn-agent, one level
+// deeper than `state` and with `mount` as its only mount. It serves no HTTP or
+// WebSocket ports and is driven through message-server requests
+fn spawn_agent_actor(state: &State, mount: &Mount) -> Result<String, String> {
+    let depth = state.agent_depth + 1;
+    let manifest_content = format!(
+        r#"name = "filesystem-chat-agent"
+version = "0.1.0"
+description = "Filesystem chat agent working on a delegated task"
+component_path = "/Users/colinrozzi/work/actors/filesystem-chat/target/wasm32-unknown-unknown/release/filesystem_chat.wasm"
+init_data = "/Users/colinrozzi/work/actors/filesystem-chat/assets/data/agent_{}.json"
+
+[interface]
+implements = "ntwk:theater/actor"
+requires = []
+
+[[handlers]]
+type = "runtime"
+config = {{}}
+
+[[handlers]]
+type = "http-client"
+config = {{}}
+
+[[handlers]]
+type = "supervisor"
+config = {{}}
+
+[[handlers]]
+type = "filesystem"
+config = {{ path = "/Users/colinrozzi/work/actors/filesystem-chat/assets" }}
+"#,
+        depth
+    );
+
+    let init_data = InitData {
+        store_id: state.store_id.clone(),
+        fs_path: String::new(),
+        permissions: Vec::new(),
+        backend: mount.backend,
+        mounts: vec![mount.clone()],
+        websocket_port: state.websocket_port,
+        agent_depth: depth,
+    };
+    write_file(
+        &format!("data/agent_{}.json", depth),
+        &serde_json::to_string(&init_data).unwrap(),
+    )
+    .map_err(|e| format!("Failed to create agent init data: {}", e))?;
+    write_file(&format!("data/agent_{}.toml", depth), &manifest_content)
+        .map_err(|e| format!("Failed to create agent manifest: {}", e))?;
+
+    Ok(spawn(&format!(
+        "/Users/colinrozzi/work/actors/filesystem-chat/assets/data/agent_{}.toml",
+        depth
+    )))
+}
+
+// Helper function to drive an agent with messages until it answers without
+// commands, returning its final answer
+fn drive_agent(agent_id: &String, task: &str) -> Result<String, String> {
+    let mut content = format!(
+        "You are a helper agent working on a sub-task. Complete it with the filesystem commands you need, then reply with your final answer and no commands.\n\nTask: {}",
+        task
+    );
+    for _ in 0..MAX_AGENT_TURNS {
+        let req = json!({ "type": "send_message", "content": content });
+        let response = request(agent_id, &serde_json::to_vec(&req).unwrap())
+            .map_err(|e| format!("Request to agent {} failed: {}", agent_id, e))?;
+        let response: Value = serde_json::from_slice(&response)
+            .map_err(|e| format!("Invalid response from agent {}: {}", agent_id, e))?;
+        if response["success"] != json!(true) {
+            return Err(format!("Agent {} failed: {}", agent_id, response["error"]));
+        }
+
+        let reply = &response["reply"];
+        let answer = reply["content"].as_str().unwrap_or_default().to_string();
+        if reply["fs_commands"].is_null() {
+            return Ok(answer);
+        }
+        // The agent sees the results of its commands with the next message
+        content = "Continue with the task using the command results. When it is done, reply with your final answer and no commands.".to_string();
+    }
+    Err(format!(
+        "Agent {} did not finish within {} turns",
+        agent_id, MAX_AGENT_TURNS
+    ))
+}
+
+// Helper function to write the manifest and init data of a mount's fs-proxy and
+// spawn it, returning its actor id
+fn spawn_fs_proxy(mount: &Mount) -> Result<String, String> {
+    let manifest_content = format!(
+        r#"name = "fs-proxy"
+version = "0.1.0"
+description = "A proxy actor that provides controlled access to the filesystem"
+component_path = "/Users/colinrozzi/work/actors/fs-proxy/target/wasm32-unknown-unknown/release/fs_proxy.wasm"
+init_data = "/Users/colinrozzi/work/actors/filesystem-chat/assets/data/fs_proxy_{}.json"
+
+[interface]
+implements = "ntwk:theater/actor"
+requires = []
+
+[[handlers]]
+type = "runtime"
+config = {{}}
+
+[[handlers]]
+type = "filesystem"
+config = {{ path = "{}" }}
+"#,
+        mount.name, mount.path
+    );
+
+    // Write init data to data directory
+    // init data should contain the permissions the fs-proxy should be started with
+    let init_data_path = format!("data/fs_proxy_{}.json", mount.name);
+    let fs_proxy_init_data = json!({
+        "permissions": mount.permissions
+    });
+    write_file(
+        &init_data_path,
+        &serde_json::to_string(&fs_proxy_init_data).unwrap(),
+    )
+    .map_err(|e| format!("Failed to create init data: {}", e))?;
+
+    // Write manifest to data directory
+    let manifest_path = format!("data/fs_proxy_{}.toml", mount.name);
+    write_file(&manifest_path, &manifest_content)
+        .map_err(|e| format!("Failed to create manifest: {}", e))?;
+
+    let full_manifest_path = format!(
+        "/Users/colinrozzi/work/actors/filesystem-chat/assets/data/fs_proxy_{}.toml",
+        mount.name
+    );
+    Ok(spawn(&full_manifest_path))
+}
+
+// Helper function to describe a chain event for the audit log. Event data is
+// searched for the fields of fs-proxy requests and HTTP calls; anything else
+// is shown as truncated text. The API key never appears in a summary
+fn audit_entry(meta: &MetaEvent, api_key: &str) -> AuditEntry {
+    let event = &meta.event;
+    let data: Option<Value> = serde_json::from_slice(&event.data).ok();
+    let field = |key: &str| {
+        data.as_ref()
+            .and_then(|d| find_field(d, key))
+            .and_then(|v| v.as_str())
+            .map(String::from)
+    };
+    let event_type = event.event_type.to_lowercase();
+
+    let (category, summary) = if let Some(operation) = field("operation") {
+        let path = field("path").unwrap_or_default();
+        ("fs", format!("{} {}", operation, path))
+    } else if event_type.contains("filesystem") {
+        (
+            "fs",
+            field("path").unwrap_or_else(|| event.event_type.clone()),
+        )
+    } else if event_type.contains("http-client") || event_type.contains("http_client") {
+        let method = field("method").unwrap_or_else(|| "request".to_string());
+        let uri = field("uri").or_else(|| field("url")).unwrap_or_default();
+        ("llm", format!("{} {}", method, uri))
+    } else {
+        let category = if event_type.contains("state") {
+            "state"
+        } else {
+            "other"
+        };
+        let text = String::from_utf8_lossy(&event.data);
+        (category, text.chars().take(AUDIT_SUMMARY_CHARS).collect())
+    };
+
+    let summary = if api_key.is_empty() {
+        summary
+    } else {
+        summary.replace(api_key, "[redacted]")
+    };
+    AuditEntry {
+        hash: format!("{:016x}", meta.hash),
+        parent: event.parent.map(|p| format!("{:016x}", p)),
+        event_type: event.event_type.clone(),
+        category: category.to_string(),
+        summary,
+    }
+}
+
+// Helper function to find the first value of a key anywhere in a JSON value
+fn find_field<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(map) => map
+            .get(key)
+            .or_else(|| map.values().find_map(|v| find_field(v, key))),
+        Value::Array(items) => items.iter().find_map(|v| find_field(v, key)),
+        _ => None,
+    }
+}
+
+// Helper function to hex encode the SHA-1 of some bytes
+fn sha1_hex(bytes: &[u8]) -> String {
+    Sha1::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Helper function to render messages as a Markdown transcript, with commands
+// and their results in collapsible sections
+fn export_markdown(messages: &[Message]) -> String {
+    let mut out = String::from("# Conversation\n");
+    for message in messages {
+        let role = match message.role.as_str() {
+            "user" => "User",
+            "assistant" => "Assistant",
+            other => other,
+        };
+        out.push_str(&format!(
+            "\n## {}\n\n{}\n",
+            role,
+            message.content.trim_end()
+        ));
+
+        if let Some(attachments) = &message.attachments {
+            let names: Vec<&str> = attachments.iter().map(|a| a.name.as_str()).collect();
+            out.push_str(&format!("\n*Attachments: {}*\n", names.join(", ")));
+        }
+
+        let Some(results) = &message.fs_results else {
+            continue;
+        };
+        let summary: Vec<String> = results
+            .iter()
+            .map(|r| format!("{} {}", r.operation, r.path))
+            .collect();
+        out.push_str(&format!(
+            "\n<details>\n<summary>{} command(s): {}</summary>\n",
+            results.len(),
+            summary.join(", ")
+        ));
+        if let Some(transaction) = &message.transaction {
+            out.push_str(&format!("\nTransaction: {:?}\n", transaction.status));
+        }
+        for result in results {
+            let status = if result.success { "ok" } else { "failed" };
+            out.push_str(&format!(
+                "\n**{}** `{}`: {}\n",
+                result.operation, result.path, status
+            ));
+            match (&result.data, &result.media_type, &result.error) {
+                (_, _, Some(error)) => out.push_str(&format!("\n> {}\n", error)),
+                (Some(_), Some(media_type), _) => {
+                    out.push_str(&format!("\n*{} content omitted*\n", media_type))
+                }
+                (Some(data), None, _) => {
+                    let fence = code_fence(data);
+                    out.push_str(&format!("\n{}\n{}\n{}\n", fence, data.trim_end(), fence));
+                }
+                _ => {}
+            }
+        }
+        out.push_str("\n</details>\n");
+    }
+    out
+}
+
+// Helper function to check every message of an imported tree before any is
+// saved
+fn check_import_tree(node: &MessageNode) -> Result<(), String> {
+    if node.message.role != "user" && node.message.role != "assistant" {
+        return Err(format!("Invalid message role: {}", node.message.role));
+    }
+    node.children.iter().try_for_each(check_import_tree)
+}
+
+// Helper function to prepare a message for an imported conversation. Snapshots
+// and hashes name store keys and files of the exporting instance's sandbox, so
+// they are dropped: undo and the change log only cover local changes
+fn imported_message(message: &Message, parent: Option<String>, conversation_id: &str) -> Message {
+    let mut message = message.clone();
+    message.id = None;
+    // The first message belongs to the new conversation
+    message.conversation = match parent {
+        Some(_) => None,
+        None => Some(conversation_id.to_string()),
+    };
+    message.parent = parent;
+    for result in message.fs_results.iter_mut().flatten() {
+        result.snapshots = None;
+        result.hashes = None;
+    }
+    message
+}
+
+// Helper function to turn an Anthropic messages array into a single chain of
+// messages. Only the text blocks of array content are kept
+fn import_messages_array(messages: &[Value]) -> Result<MessageNode, String> {
+    let mut nodes = messages
+        .iter()
+        .map(|message| {
+            let role = message["role"]
+                .as_str()
+                .ok_or("Message without a role")?
+                .to_string();
+            let content = match &message["content"] {
+                Value::String(text) => text.clone(),
+                Value::Array(blocks) => blocks
+                    .iter()
+                    .filter(|block| block["type"] == "text")
+                    .filter_map(|block| block["text"].as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n\n"),
+                _ => return Err(format!("Message without content: {}", message)),
+            };
+            Ok(Message::new(role, content, None))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let mut node: Option<MessageNode> = None;
+    while let Some(message) = nodes.pop() {
+        node = Some(MessageNode {
+            message,
+            children: node.into_iter().collect(),
+        });
+    }
+    node.ok_or_else(|| "No messages to import".to_string())
+}
+
+// Helper function to split text into lowercase words for the search index
+fn search_terms(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() > 1)
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+// Helper function to cut the text around the first word starting with one of
+// `terms`
+fn snippet(text: &str, terms: &[String]) -> Option<String> {
+    let mut offset = 0;
+    let start = text
+        .split(|c: char| !c.is_alphanumeric())
+        .find_map(|word| {
+            let start = offset;
+            offset += word.len()
+                + text[offset + word.len()..]
+                    .chars()
+                    .next()
+                    .map_or(0, char::len_utf8);
+            let word = word.to_lowercase();
+            terms
+                .iter()
+                .any(|t| word.starts_with(t.as_str()))
+                .then_some(start)
+        })?;
+
+    let before: Vec<char> = text[..start]
+        .chars()
+        .rev()
+        .take(SNIPPET_CONTEXT_CHARS + 1)
+        .collect();
+    let after: Vec<char> = text[start..]
+        .chars()
+        .take(SNIPPET_CONTEXT_CHARS * 2 + 1)
+        .collect();
+    let mut snippet = String::new();
+    if before.len() > SNIPPET_CONTEXT_CHARS {
+        snippet.push('…');
+    }
+    snippet.extend(before.iter().take(SNIPPET_CONTEXT_CHARS).rev());
+    snippet.extend(after.iter().take(SNIPPET_CONTEXT_CHARS * 2));
+    if after.len() > SNIPPET_CONTEXT_CHARS * 2 {
+        snippet.push('…');
+    }
+    Some(snippet.split_whitespace().collect::<Vec<_>>().join(" "))
+}
+
+// Helper function to render messages as plain text for the summary model, with
+// long messages cut short
+fn transcript(messages: &[Message]) -> String {
+    messages
+        .iter()
+        .map(|m| {
+            let role = if m.role == "user" {
+                "User"
+            } else {
+                "Assistant"
+            };
+            let mut content: String = m.content.chars().take(SUMMARY_MAX_MESSAGE_CHARS).collect();
+            if content.len() < m.content.len() {
+                content.push('…');
+            }
+            format!("{}: {}", role, content)
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+// Helper function to encode a message for the store: its JSON without the id,
+// which is the key it is stored under, and without empty fields
+fn encode_message(msg: &Message) -> Result<Vec<u8>, serde_json::Error> {
+    let mut value = serde_json::to_value(msg)?;
+    if let Value::Object(fields) = &mut value {
+        fields.retain(|name, field| name != "id" && !field.is_null());
+    }
+    serde_json::to_vec(&value)
+}
+
+// Helper function to decode a stored message, in either the compact or the
+// full JSON form
+fn decode_message(bytes: &[u8], id: String) -> Result<Message, serde_json::Error> {
+    let msg: Message = serde_json::from_slice(bytes)?;
+    Ok(msg.with_id(id))
+}
+
+// Helper function to pick a Markdown code fence longer than any run of
+// backticks in `text`
+fn code_fence(text: &str) -> String {
+    let longest = text
+        .split(|c| c != '`')
+        .map(|run| run.len())
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+// Helper function to collect every root-to-leaf path of a message tree that
+// ends with an assistant reply. A branch ending in an unanswered message is
+// skipped rather than cut back, which would repeat part of a sibling branch
+fn collect_branches<'a>(
+    node: &'a MessageNode,
+    path: &mut Vec<&'a Message>,
+    branches: &mut Vec<Vec<&'a Message>>,
+) {
+    path.push(&node.message);
+    if node.children.is_empty() && node.message.role == "assistant" {
+        branches.push(path.clone());
+    }
+    for child in &node.children {
+        collect_branches(child, path, branches);
+    }
+    path.pop();
+}
+
+// Helper function to tell the operations that change the filesystem
+fn is_mutation(operation: &str) -> bool {
+    matches!(
+        operation,
+        "write-file"
+            | "edit-file"
+            | "create-dir"
+            | "delete-file"
+            | "delete-dir"
+            | "move-file"
+            | "copy-file"
+            | "copy-dir"
+            | "spawn-agent"
+            | "undo"
+    )
+}
+
+// Helper function to classify a file change, None when the file exists on
+// neither side
+fn change_status(old: Option<&str>, new: Option<&str>) -> Option<&'static str> {
+    match (old, new) {
+        (None, None) => None,
+        (None, Some(_)) => Some("added"),
+        (Some(_), None) => Some("deleted"),
+        (Some(old), Some(new)) if old == new => Some("unchanged"),
+        (Some(_), Some(_)) => Some("modified"),
+    }
+}
+
+// Helper function to preview the change to one file
+fn file_preview(path: &str, old: Option<&str>, new: Option<&str>) -> FilePreview {
+    FilePreview {
+        path: path.to_string(),
+        status: change_status(old, new).unwrap_or("unchanged").to_string(),
+        diff: unified_diff(path, old, new),
+    }
+}
+
+// Helper function to replace the single occurrence of `old_text` in a file
+fn apply_edit(path: &str, text: &str, old_text: &str, new_text: &str) -> Result<String, String> {
+    if old_text.is_empty() {
+        return Err(format!("Empty <old_text> for {}", path));
+    }
+    match text.matches(old_text).count() {
+        0 => Err(format!("<old_text> not found in {}", path)),
+        1 => Ok(text.replacen(old_text, new_text, 1)),
+        n => Err(format!(
+            "<old_text> matches {} times in {}, include more context to make it unique",
+            n, path
+        )),
+    }
+}
+
+// Helper function to walk an audit log from its head, checking every record's
+// hash and its link to the record before it. Returns the number of records
+// checked
+fn verify_audit_chain(
+    head: &AuditHead,
+    load: impl Fn(&str) -> Result<Vec<u8>, String>,
+) -> Result<u64, String> {
+    let mut key = Some(head.key.clone());
+    let mut expected_hash = Some(head.hash.clone());
+    let mut expected_sequence = head.sequence;
+    let mut checked = 0;
+
+    while let Some(k) = key {
+        let record: AuditRecord = load(&k)
+            .map_err(|e| format!("Record {} is missing: {}", expected_sequence, e))
+            .and_then(|bytes| {
+                serde_json::from_slice(&bytes)
+                    .map_err(|e| format!("Record {} is unreadable: {}", expected_sequence, e))
+            })?;
+        if record.sequence != expected_sequence {
+            return Err(format!(
+                "Expected record {} but found record {}",
+                expected_sequence, record.sequence
+            ));
+        }
+        if record.hash != record.compute_hash() {
+            return Err(format!("Record {} has been altered", record.sequence));
+        }
+        if expected_hash.as_ref() != Some(&record.hash) {
+            return Err(format!(
+                "Record {} doesn't match the hash the record after it links to",
+                record.sequence
+            ));
+        }
+        checked += 1;
+
+        if record.sequence == 0 {
+            if record.previous_key.is_some() {
+                return Err("The first record links to an earlier one".to_string());
+            }
+            break;
+        }
+        if record.previous_key.is_none() {
+            return Err(format!(
+                "Record {} has no link to the one before",
+                record.sequence
+            ));
+        }
+        key = record.previous_key;
+        expected_hash = record.previous_hash;
+        expected_sequence -= 1;
+    }
+    Ok(checked)
+}
+
+// Helper function to detect binary data: NUL bytes or invalid UTF-8
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.contains(&0) || std::str::from_utf8(bytes).is_err()
+}
+
+// Helper function to pass attachments to the model as document blocks titled
+// with their names. Attachments that were left out are noted in a text block
+fn attachment_blocks(attachments: &[Attachment]) -> Vec<ContentBlock> {
+    attachments
+        .iter()
+        .map(|a| match &a.content {
+            // The API refuses empty documents
+            Some(content) if !content.is_empty() => ContentBlock::text_document(&a.name, content),
+            Some(_) => ContentBlock::Text {
+                text: format!("Attachment {} ({}) is empty", a.name, a.source),
+            },
+            None => ContentBlock::Text {
+                text: format!(
+                    "Attachment {} ({}) omitted: {}",
+                    a.name,
+                    a.source,
+                    a.error.as_deref().unwrap_or_default()
+                ),
+            },
+        })
+        .collect()
+}
+
+// Helper function to parse a JSON array of filesystem commands
+fn parse_command_list(value: &Value) -> Option<Vec<FsCommand>> {
+    let commands: Vec<FsCommand> = value
+        .as_array()?
+        .iter()
+        .filter_map(|cmd| serde_json::from_value(cmd.clone()).ok())
+        .collect();
+
+    if commands.is_empty() {
+        None
+    } else {
+        Some(commands)
+    }
+}
+
+// Helper function to split a request uri into its path segments and query string
+fn split_uri(uri: &str) -> (Vec<&str>, &str) {
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let segments = path.split('/').filter(|s| !s.is_empty()).collect();
+    (segments, query)
+}
+
+// Helper function to look up a percent-decoded query string parameter
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
                     }
+                    Err(_) => decoded.push(b'%'),
                 }
-                _ => {
-                    log(&format!("Received unknown event type: {}", event.type_));
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Helper function to translate a glob into a regex: `*` and `?` match within a
+// path segment, `**` across segments
+fn glob_to_regex(glob: &str) -> Result<Regex, regex::Error> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
                 }
             }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(&c.to_string())),
         }
+    }
+    re.push('$');
+    Regex::new(&re)
+}
 
-        serde_json::to_vec(&state).unwrap()
+// Patterns containing a slash match the path below the listed directory, other
+// patterns just the entry name. The `[^/]` that `*` and `?` translate to is not
+// a slash of the glob
+fn glob_matches(pattern: &Regex, root: &str, entry: &FsEntry) -> bool {
+    if pattern.as_str().replace("[^/]", "").contains('/') {
+        pattern.is_match(relative_to(root, &entry.path))
+    } else {
+        pattern.is_match(&entry.name)
     }
+}
 
-    fn handle_request(_message: Json, state: Json) -> (Json, Json) {
-        let state: State = serde_json::from_slice(&state).unwrap();
+fn relative_to<'a>(root: &str, path: &'a str) -> &'a str {
+    // The root of a mount is `name:` or `name:.`
+    let root = root.trim_end_matches('/');
+    let root = root
+        .strip_suffix(":.")
+        .map_or(root, |r| &root[..r.len() + 1]);
+    match root {
+        "" | "." => path,
+        root if root.ends_with(':') => path.strip_prefix(root).unwrap_or(path),
+        root => path
+            .strip_prefix(root)
+            .map(|p| p.trim_start_matches('/'))
+            .unwrap_or(path),
+    }
+}
 
-        // Handle messages that require responses
-        let response = json!({
-            "success": true,
-            "message": "Acknowledged"
-        });
+// Helper function to render list-files entries as a compact indented tree
+fn render_entry_tree(root: &str, entries: &[FsEntry]) -> String {
+    let mut lines = vec![format!("{}/", root.trim_end_matches('/'))];
+    for entry in entries {
+        let level = relative_to(root, &entry.path).matches('/').count() + 1;
+        let mut line = format!("{}{}", "  ".repeat(level), entry.name);
+        if entry.kind == "dir" {
+            line.push('/');
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
 
-        (
-            serde_json::to_vec(&response).unwrap(),
-            serde_json::to_vec(&state).unwrap(),
-        )
+// Helper function to join a directory path and an entry name
+fn join_path(dir: &str, name: &str) -> String {
+    match dir.trim_end_matches('/') {
+        "" | "." => name.to_string(),
+        dir if dir.ends_with(':') => format!("{}{}", dir, name),
+        dir if dir.ends_with(":.") => format!("{}{}", &dir[..dir.len() - 1], name),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+// Helper function to create a JSON HTTP response
+fn json_response(status: u16, body: Value) -> HttpResponse {
+    HttpResponse {
+        status,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: Some(serde_json::to_vec(&body).unwrap()),
+    }
+}
+
+fn error_response(status: u16, error: &str) -> HttpResponse {
+    json_response(status, json!({ "error": error }))
+}
+
+// Helper function to parse the JSON body of an HTTP request
+fn parse_body(request: &HttpRequest) -> Result<Value, HttpResponse> {
+    match &request.body {
+        Some(body) if !body.is_empty() => serde_json::from_slice(body)
+            .map_err(|e| error_response(400, &format!("Invalid JSON body: {}", e))),
+        _ => Err(error_response(400, "Missing request body")),
     }
 }
 
+// Helper function to build the HTTP response for a processed message
+fn message_state_response(state: &State, message_state: MessageState) -> HttpResponse {
+    let status = match message_state.status {
+        MessageStatus::Completed => 201,
+        _ => 502,
+    };
+    json_response(status, message_state_json(state, &message_state))
+}
+
+// Helper function to pair a processed message with the assistant's reply
+fn message_state_json(state: &State, message_state: &MessageState) -> Value {
+    // The reply is the new head when the assistant answered this message. An
+    // assistant message only has its commands run, so it gets no reply
+    let reply = match (&state.head, &message_state.message.id) {
+        (Some(head), Some(id)) if head != id && message_state.message.role == "user" => {
+            state.load_message(head).ok()
+        }
+        _ => None,
+    };
+    json!({
+        "message_state": message_state,
+        "reply": reply,
+    })
+}
+
+// Helper function to answer an actor request that sent a message, which only
+// succeeds when the assistant replied
+fn actor_reply(state: &State, message_state: &MessageState) -> Value {
+    let mut body = message_state_json(state, message_state);
+    body["success"] = json!(matches!(message_state.status, MessageStatus::Completed));
+    body["error"] = json!(message_state.last_error);
+    body
+}
+
 // Helper function to create the WebSocket response with message state
 fn send_message_state_update(
     state: State,
@@ -1016,3 +5451,772 @@ fn send_message_state_update(
 struct Component;
 
 bindings::export!(Component with_types_in bindings);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(path: &str, kind: &str) -> FsEntry {
+        FsEntry {
+            name: path.rsplit('/').next().unwrap_or(path).to_string(),
+            path: path.to_string(),
+            kind: kind.to_string(),
+        }
+    }
+
+    #[test]
+    fn glob_star_stays_within_a_segment() {
+        let re = glob_to_regex("*.rs").unwrap();
+        assert!(re.is_match("lib.rs"));
+        assert!(!re.is_match("src/lib.rs"));
+        assert!(!re.is_match("lib.rsx"));
+
+        let re = glob_to_regex("src/?.rs").unwrap();
+        assert!(re.is_match("src/a.rs"));
+        assert!(!re.is_match("src/ab.rs"));
+    }
+
+    #[test]
+    fn glob_double_star_crosses_segments() {
+        let re = glob_to_regex("src/**/*.rs").unwrap();
+        assert!(re.is_match("src/lib.rs"));
+        assert!(re.is_match("src/a/b/lib.rs"));
+        assert!(!re.is_match("tests/lib.rs"));
+
+        let re = glob_to_regex("**").unwrap();
+        assert!(re.is_match("a/b/c"));
+    }
+
+    #[test]
+    fn glob_escapes_regex_characters() {
+        let re = glob_to_regex("a+b(1).txt").unwrap();
+        assert!(re.is_match("a+b(1).txt"));
+        assert!(!re.is_match("aab(1)xtxt"));
+    }
+
+    #[test]
+    fn glob_matches_name_or_relative_path() {
+        let re = glob_to_regex("*.rs").unwrap();
+        assert!(glob_matches(
+            &re,
+            "project:src",
+            &entry("project:src/a/lib.rs", "file")
+        ));
+
+        let re = glob_to_regex("a/*.rs").unwrap();
+        assert!(glob_matches(
+            &re,
+            "project:src",
+            &entry("project:src/a/lib.rs", "file")
+        ));
+        assert!(glob_matches(
+            &re,
+            "project:",
+            &entry("project:a/lib.rs", "file")
+        ));
+        assert!(glob_matches(
+            &re,
+            "project:.",
+            &entry("project:a/lib.rs", "file")
+        ));
+        assert!(!glob_matches(
+            &re,
+            "project:src",
+            &entry("project:src/b/a/lib.rs", "file")
+        ));
+    }
+
+    fn read_command(offset: Option<usize>, limit: Option<usize>) -> FsCommand {
+        let mut cmd = FsCommand::new("read-file", "project:a.txt");
+        cmd.offset = offset;
+        cmd.limit = limit;
+        cmd
+    }
+
+    #[test]
+    fn read_lines_returns_small_files_unchanged() {
+        let text = "one\ntwo\n";
+        assert_eq!(read_lines(text, &read_command(None, None)), text);
+    }
+
+    #[test]
+    fn read_lines_numbers_a_range() {
+        let text = "one\ntwo\nthree\nfour\n";
+        assert_eq!(
+            read_lines(text, &read_command(Some(1), Some(2))),
+            "2\ttwo\n3\tthree\n[lines 2-3 of 4]"
+        );
+
+        let mut cmd = read_command(Some(3), None);
+        cmd.line_numbers = Some(false);
+        assert_eq!(read_lines(text, &cmd), "four\n[lines 4-4 of 4]");
+    }
+
+    #[test]
+    fn read_lines_truncates_at_the_byte_limit() {
+        let line = "x".repeat(READ_MAX_BYTES / 3);
+        let text = [line.as_str(); 5].join("\n");
+        let output = read_lines(&text, &read_command(None, None));
+        assert!(output.len() <= READ_MAX_BYTES + 100);
+        assert!(output
+            .ends_with("[truncated: showing lines 1-2 of 5, use <offset>2</offset> to read more]"));
+    }
+
+    #[test]
+    fn read_lines_cuts_an_oversize_first_line() {
+        let text = format!(
+            "short\n{}é{}\nnext",
+            "x".repeat(READ_MAX_BYTES - 1),
+            "y".repeat(10)
+        );
+        let output = read_lines(&text, &read_command(Some(1), None));
+        let (shown, note) = output.rsplit_once('\n').unwrap();
+        assert!(shown.starts_with("2\txxx"));
+        assert!(shown.len() <= READ_MAX_BYTES);
+        // The cut falls before the two-byte é and the note points at it
+        let cut = shown.len() - "2\t".len();
+        assert_eq!(
+            note,
+            format!(
+                "[truncated: line 2 is longer than {} bytes, use <offset>{}</offset><unit>bytes</unit> to read more]",
+                READ_MAX_BYTES,
+                "short\n".len() + cut
+            )
+        );
+        assert!(text.is_char_boundary("short\n".len() + cut));
+    }
+
+    #[test]
+    fn read_bytes_range_widens_to_char_boundaries() {
+        let text = "aé€b";
+        assert_eq!(read_bytes_range(text, &read_command(None, None)), text);
+        // Offset 2 is inside é and the limit of 1 ends inside €
+        assert_eq!(
+            read_bytes_range(text, &read_command(Some(2), Some(1))),
+            "é\n[bytes 1-3 of 7]"
+        );
+        assert_eq!(
+            read_bytes_range(text, &read_command(Some(4), Some(2))),
+            "€\n[bytes 3-6 of 7]"
+        );
+        assert_eq!(
+            read_bytes_range(text, &read_command(Some(100), None)),
+            "\n[bytes 7-7 of 7]"
+        );
+    }
+
+    #[test]
+    fn search_terms_splits_into_lowercase_words() {
+        assert_eq!(
+            search_terms("Fix the Parser's read_lines, a b"),
+            vec!["fix", "the", "parser", "read", "lines"]
+        );
+    }
+
+    #[test]
+    fn snippet_matches_word_prefixes() {
+        let terms = vec!["pars".to_string()];
+        assert_eq!(
+            snippet("Fix the parser", &terms).as_deref(),
+            Some("Fix the parser")
+        );
+        assert_eq!(snippet("Fix the sparse array", &terms), None);
+        assert_eq!(snippet("", &terms), None);
+    }
+
+    #[test]
+    fn snippet_cuts_context_around_the_match() {
+        let terms = vec!["needle".to_string()];
+        let text = format!(
+            "{} needle {}",
+            "a".repeat(SNIPPET_CONTEXT_CHARS * 2),
+            "b".repeat(SNIPPET_CONTEXT_CHARS * 3)
+        );
+        let snippet = snippet(&text, &terms).unwrap();
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains(" needle "));
+        assert_eq!(snippet.chars().count(), SNIPPET_CONTEXT_CHARS * 3 + 2);
+    }
+
+    #[test]
+    fn snippet_handles_multibyte_text() {
+        let terms = vec!["über".to_string()];
+        assert_eq!(
+            snippet("Ça va — Über\nalles", &terms).as_deref(),
+            Some("Ça va — Über alles")
+        );
+    }
+
+    #[test]
+    fn percent_decode_decodes_escapes_and_plus() {
+        assert_eq!(percent_decode("a%20b+c"), "a b c");
+        assert_eq!(
+            percent_decode("project%3Asrc%2Flib.rs"),
+            "project:src/lib.rs"
+        );
+        assert_eq!(percent_decode("%C3%A9"), "é");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%4"), "%4");
+        assert_eq!(percent_decode("%zz1"), "%zz1");
+        assert_eq!(percent_decode("%é"), "%é");
+    }
+
+    #[test]
+    fn query_param_finds_decoded_values() {
+        assert_eq!(
+            query_param("path=a%2Fb&depth=2", "path").as_deref(),
+            Some("a/b")
+        );
+        assert_eq!(query_param("flag&depth=2", "flag").as_deref(), Some(""));
+        assert_eq!(query_param("depth=2", "path"), None);
+    }
+
+    #[test]
+    fn apply_edit_replaces_a_unique_match() {
+        assert_eq!(
+            apply_edit("a.rs", "let x = 1;\nlet y = 2;\n", "y = 2", "y = 3"),
+            Ok("let x = 1;\nlet y = 3;\n".to_string())
+        );
+        assert_eq!(
+            apply_edit("a.rs", "keep\ndrop\n", "drop\n", ""),
+            Ok("keep\n".to_string())
+        );
+    }
+
+    #[test]
+    fn apply_edit_rejects_missing_repeated_and_empty_text() {
+        assert_eq!(
+            apply_edit("a.rs", "abc", "x", "y"),
+            Err("<old_text> not found in a.rs".to_string())
+        );
+        assert_eq!(
+            apply_edit("a.rs", "x x x", "x", "y"),
+            Err(
+                "<old_text> matches 3 times in a.rs, include more context to make it unique"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            apply_edit("a.rs", "abc", "", "y"),
+            Err("Empty <old_text> for a.rs".to_string())
+        );
+    }
+
+    fn node(role: &str, content: &str, children: Vec<MessageNode>) -> MessageNode {
+        MessageNode {
+            message: Message::new(role.to_string(), content.to_string(), None),
+            children,
+        }
+    }
+
+    fn contents(branches: &[Vec<&Message>]) -> Vec<Vec<String>> {
+        branches
+            .iter()
+            .map(|branch| branch.iter().map(|m| m.content.clone()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn collect_branches_follows_every_answered_path() {
+        let tree = node(
+            "user",
+            "q",
+            vec![
+                node("assistant", "a1", vec![]),
+                node(
+                    "assistant",
+                    "a2",
+                    vec![node("user", "q2", vec![node("assistant", "a3", vec![])])],
+                ),
+            ],
+        );
+        let mut branches = Vec::new();
+        collect_branches(&tree, &mut Vec::new(), &mut branches);
+        assert_eq!(
+            contents(&branches),
+            vec![vec!["q", "a1"], vec!["q", "a2", "q2", "a3"]]
+        );
+    }
+
+    #[test]
+    fn collect_branches_skips_unanswered_leaves() {
+        let tree = node(
+            "user",
+            "q",
+            vec![node(
+                "assistant",
+                "a",
+                vec![
+                    node("user", "pending", vec![]),
+                    node("user", "q2", vec![node("assistant", "a2", vec![])]),
+                ],
+            )],
+        );
+        let mut branches = Vec::new();
+        collect_branches(&tree, &mut Vec::new(), &mut branches);
+        assert_eq!(contents(&branches), vec![vec!["q", "a", "q2", "a2"]]);
+
+        let unanswered = node("user", "q", vec![]);
+        let mut branches = Vec::new();
+        collect_branches(&unanswered, &mut Vec::new(), &mut branches);
+        assert!(branches.is_empty());
+    }
+
+    #[test]
+    fn import_messages_array_chains_messages() {
+        let messages = vec![
+            json!({"role": "user", "content": "hello"}),
+            json!({"role": "assistant", "content": [
+                {"type": "text", "text": "one"},
+                {"type": "tool_use", "id": "t", "name": "x", "input": {}},
+                {"type": "text", "text": "two"}
+            ]}),
+        ];
+        let root = import_messages_array(&messages).unwrap();
+        assert_eq!(root.message.role, "user");
+        assert_eq!(root.message.content, "hello");
+        assert_eq!(root.children.len(), 1);
+        let reply = &root.children[0];
+        assert_eq!(reply.message.role, "assistant");
+        assert_eq!(reply.message.content, "one\n\ntwo");
+        assert!(reply.children.is_empty());
+    }
+
+    #[test]
+    fn import_messages_array_rejects_invalid_messages() {
+        assert_eq!(
+            import_messages_array(&[]).err().as_deref(),
+            Some("No messages to import")
+        );
+        assert_eq!(
+            import_messages_array(&[json!({"content": "hi"})])
+                .err()
+                .as_deref(),
+            Some("Message without a role")
+        );
+        assert!(
+            import_messages_array(&[json!({"role": "user", "content": 1})])
+                .unwrap_err()
+                .starts_with("Message without content")
+        );
+    }
+
+    #[test]
+    fn imported_message_drops_sandbox_state() {
+        let mut result = FsResult::succeeded("write-file".into(), "repo:a.txt".into(), None);
+        result.snapshots = Some(vec![Snapshot {
+            path: "repo:a.txt".into(),
+            key: "foreign-key".into(),
+        }]);
+        result.hashes = Some(vec![PathHashes {
+            path: "repo:a.txt".into(),
+            before: None,
+            after: Some("abc".into()),
+        }]);
+        let mut message = Message::new("assistant".into(), "done".into(), Some("old".into()))
+            .with_id("old-id".into());
+        message.fs_results = Some(vec![result]);
+
+        let imported = imported_message(&message, None, "conversation-7");
+        assert_eq!(imported.id, None);
+        assert_eq!(imported.parent, None);
+        assert_eq!(imported.conversation.as_deref(), Some("conversation-7"));
+        let results = imported.fs_results.unwrap();
+        assert!(results[0].success);
+        assert!(results[0].snapshots.is_none());
+        assert!(results[0].hashes.is_none());
+
+        let child = imported_message(&message, Some("parent".into()), "conversation-7");
+        assert_eq!(child.parent.as_deref(), Some("parent"));
+        assert_eq!(child.conversation, None);
+    }
+
+    fn command_xml(operation: &str, path: &str) -> String {
+        format!(
+            "<fs-command><operation>{}</operation><path>{}</path></fs-command>",
+            operation, path
+        )
+    }
+
+    #[test]
+    fn transaction_commands_covers_only_the_block() {
+        let reply = format!(
+            "First {}, then <fs-transaction>{}{}</fs-transaction> and {}",
+            command_xml("read-file", "notes.txt"),
+            command_xml("write-file", "a.txt"),
+            command_xml("edit-file", "b.txt"),
+            command_xml("list-files", "."),
+        );
+        let commands = parse_fs_commands(&reply).unwrap();
+        let range = transaction_commands(&reply).unwrap();
+        assert_eq!(range, 1..3);
+        let operations: Vec<&str> = commands[range]
+            .iter()
+            .map(|cmd| cmd.operation.as_str())
+            .collect();
+        assert_eq!(operations, vec!["write-file", "edit-file"]);
+    }
+
+    #[test]
+    fn transaction_commands_needs_commands_in_a_block() {
+        let plain = command_xml("write-file", "a.txt");
+        assert_eq!(transaction_commands(&plain), None);
+        let empty = format!("{}<fs-transaction></fs-transaction>", plain);
+        assert_eq!(transaction_commands(&empty), None);
+        let unclosed = format!("{}<fs-transaction>{}", plain, plain);
+        assert_eq!(transaction_commands(&unclosed), Some(1..2));
+    }
+
+    #[test]
+    fn attachment_blocks_are_titled_documents() {
+        let attachments = vec![
+            Attachment::from_bytes("say \"hi\">.txt".into(), "upload", b"hello"),
+            Attachment::from_bytes("empty.txt".into(), "file", b""),
+            Attachment::from_bytes("image.png".into(), "upload", &[0, 1, 2]),
+        ];
+        let blocks = serde_json::to_value(attachment_blocks(&attachments)).unwrap();
+        assert_eq!(
+            blocks[0],
+            json!({
+                "type": "document",
+                "source": {"type": "text", "media_type": "text/plain", "data": "hello"},
+                "title": "say \"hi\">.txt",
+            })
+        );
+        assert_eq!(
+            blocks[1],
+            json!({"type": "text", "text": "Attachment empty.txt (file) is empty"})
+        );
+        assert_eq!(
+            blocks[2],
+            json!({
+                "type": "text",
+                "text": "Attachment image.png (upload) omitted: Binary files cannot be attached",
+            })
+        );
+    }
+
+    fn cached(id: usize) -> Message {
+        Message::new("user".into(), format!("message {}", id), None).with_id(format!("id-{}", id))
+    }
+
+    #[test]
+    fn message_cache_evicts_the_least_recently_used() {
+        let mut cache = MessageCache {
+            tick: 0,
+            entries: HashMap::new(),
+        };
+        for i in 0..MESSAGE_CACHE_CAPACITY {
+            cache.insert(cached(i));
+        }
+        // Using the oldest entry makes the second one the least recently used
+        assert_eq!(cache.get("id-0").unwrap().content, "message 0");
+        cache.insert(cached(MESSAGE_CACHE_CAPACITY));
+        assert_eq!(cache.entries.len(), MESSAGE_CACHE_CAPACITY);
+        assert!(cache.get("id-0").is_some());
+        assert!(cache.get("id-1").is_none());
+        assert!(cache
+            .get(&format!("id-{}", MESSAGE_CACHE_CAPACITY))
+            .is_some());
+
+        // Inserting a cached message again evicts nothing
+        cache.insert(cached(2));
+        assert_eq!(cache.entries.len(), MESSAGE_CACHE_CAPACITY);
+        assert!(cache.get("id-3").is_some());
+
+        // Messages without an id are not cached
+        cache.insert(Message::new("user".into(), "no id".into(), None));
+        assert_eq!(cache.entries.len(), MESSAGE_CACHE_CAPACITY);
+    }
+
+    #[test]
+    fn encoded_messages_leave_out_the_id_and_empty_fields() {
+        let mut message = Message::new("user".into(), "hello".into(), Some("parent".into()))
+            .with_id("key".into());
+        message.conversation = Some("conversation-1".into());
+        let bytes = encode_message(&message).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&bytes).unwrap(),
+            json!({
+                "role": "user",
+                "content": "hello",
+                "parent": "parent",
+                "conversation": "conversation-1",
+            })
+        );
+
+        let decoded = decode_message(&bytes, "key".into()).unwrap();
+        assert_eq!(decoded.id.as_deref(), Some("key"));
+        assert_eq!(decoded.parent.as_deref(), Some("parent"));
+        assert_eq!(decoded.content, "hello");
+        assert_eq!(
+            serde_json::to_value(&decoded).unwrap(),
+            serde_json::to_value(&message).unwrap()
+        );
+    }
+
+    #[test]
+    fn decode_message_reads_the_full_form() {
+        let message = Message::new("assistant".into(), "hi".into(), None).with_id("old".into());
+        let bytes = serde_json::to_vec(&message).unwrap();
+        let decoded = decode_message(&bytes, "key".into()).unwrap();
+        assert_eq!(decoded.id.as_deref(), Some("key"));
+        assert_eq!(decoded.role, "assistant");
+    }
+
+    // An audit log of `count` linked records, stored under `key-<sequence>`
+    fn audit_log(count: u64) -> (AuditHead, HashMap<String, AuditRecord>) {
+        let mut records = HashMap::new();
+        let mut previous: Option<AuditRecord> = None;
+        for sequence in 0..count {
+            let mut record = AuditRecord {
+                sequence,
+                message_id: format!("message-{}", sequence),
+                role: "assistant".into(),
+                operation: "write-file".into(),
+                path: "repo:a.txt".into(),
+                before: None,
+                after: Some(format!("hash-{}", sequence)),
+                chain_event: None,
+                previous_key: previous.as_ref().map(|r| format!("key-{}", r.sequence)),
+                previous_hash: previous.as_ref().map(|r| r.hash.clone()),
+                hash: String::new(),
+            };
+            record.hash = record.compute_hash();
+            records.insert(format!("key-{}", sequence), record.clone());
+            previous = Some(record);
+        }
+        let last = previous.unwrap();
+        let head = AuditHead {
+            key: format!("key-{}", last.sequence),
+            hash: last.hash,
+            sequence: last.sequence,
+        };
+        (head, records)
+    }
+
+    fn verify(head: &AuditHead, records: &HashMap<String, AuditRecord>) -> Result<u64, String> {
+        verify_audit_chain(head, |key| {
+            records
+                .get(key)
+                .map(|record| serde_json::to_vec(record).unwrap())
+                .ok_or_else(|| "not found".to_string())
+        })
+    }
+
+    #[test]
+    fn audit_chain_verifies() {
+        let (head, records) = audit_log(3);
+        assert_eq!(verify(&head, &records), Ok(3));
+    }
+
+    #[test]
+    fn audit_hash_covers_every_field() {
+        let (_, records) = audit_log(1);
+        let record = &records["key-0"];
+        assert_eq!(record.hash, record.compute_hash());
+        let altered = AuditRecord {
+            path: "repo:b.txt".into(),
+            ..record.clone()
+        };
+        assert_ne!(altered.compute_hash(), record.hash);
+    }
+
+    #[test]
+    fn audit_chain_detects_a_modified_record() {
+        let (head, mut records) = audit_log(3);
+        records.get_mut("key-1").unwrap().after = Some("forged".into());
+        assert_eq!(
+            verify(&head, &records),
+            Err("Record 1 has been altered".to_string())
+        );
+
+        // Rehashing the forged record breaks the link from the record after it
+        let record = records.get_mut("key-1").unwrap();
+        record.hash = record.compute_hash();
+        assert_eq!(
+            verify(&head, &records),
+            Err("Record 1 doesn't match the hash the record after it links to".to_string())
+        );
+    }
+
+    #[test]
+    fn audit_chain_detects_a_broken_link() {
+        let (head, mut records) = audit_log(3);
+        records.remove("key-1");
+        assert!(verify(&head, &records)
+            .unwrap_err()
+            .starts_with("Record 1 is missing"));
+
+        // Skipping a record is noticed by its sequence number
+        let (head, mut records) = audit_log(3);
+        let first = records["key-0"].clone();
+        records.insert("key-1".into(), first);
+        assert_eq!(
+            verify(&head, &records),
+            Err("Expected record 1 but found record 0".to_string())
+        );
+
+        // Cutting the link, even with the hash recomputed
+        let (head, mut records) = audit_log(3);
+        let record = records.get_mut("key-2").unwrap();
+        record.previous_key = None;
+        record.hash = record.compute_hash();
+        let head = AuditHead {
+            hash: record.hash.clone(),
+            ..head
+        };
+        assert_eq!(
+            verify(&head, &records),
+            Err("Record 2 has no link to the one before".to_string())
+        );
+    }
+
+    #[test]
+    fn builtin_mount_must_be_a_dedicated_subdirectory() {
+        assert_eq!(check_builtin_mount("workspace"), Ok(()));
+        assert_eq!(check_builtin_mount("workspace/project/"), Ok(()));
+        assert!(check_builtin_mount("/workspace").is_err());
+        for path in ["", ".", "./workspace", "workspace/../data", "a//b"] {
+            assert!(check_builtin_mount(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn builtin_mount_stays_out_of_the_actor_files() {
+        for path in BUILTIN_RESERVED_PATHS {
+            assert!(check_builtin_mount(path).is_err(), "{}", path);
+        }
+        assert_eq!(
+            check_builtin_mount("data/agents"),
+            Err("'data' holds the actor's own files".to_string())
+        );
+        assert_eq!(check_builtin_mount("workspace/data"), Ok(()));
+    }
+
+    #[test]
+    fn media_type_is_detected_by_content_first() {
+        assert_eq!(
+            media_type_for("a.bin", b"\x89PNG\r\n\x1a\nrest"),
+            Some("image/png")
+        );
+        assert_eq!(
+            media_type_for("a", &[0xFF, 0xD8, 0xFF, 0xE0]),
+            Some("image/jpeg")
+        );
+        assert_eq!(media_type_for("a", b"GIF89a..."), Some("image/gif"));
+        assert_eq!(
+            media_type_for("a", b"RIFF\0\0\0\0WEBPVP8 "),
+            Some("image/webp")
+        );
+        assert_eq!(
+            media_type_for("a.png", b"%PDF-1.7"),
+            Some("application/pdf")
+        );
+    }
+
+    #[test]
+    fn media_type_falls_back_to_the_extension() {
+        assert_eq!(media_type_for("photo.JPG", &[0, 1]), Some("image/jpeg"));
+        assert_eq!(
+            media_type_for("docs/report.pdf", &[0, 1]),
+            Some("application/pdf")
+        );
+        assert_eq!(media_type_for("archive.zip", &[0, 1]), None);
+        assert_eq!(media_type_for("no_extension", &[0, 1]), None);
+    }
+
+    #[test]
+    fn file_content_data_encodes_media_only() {
+        assert_eq!(
+            file_content_data("a.txt", b"hello".to_vec()),
+            (Some("hello".to_string()), None)
+        );
+
+        let png = b"\x89PNG\r\n\x1a\n\0".to_vec();
+        assert_eq!(
+            file_content_data("a.png", png.clone()),
+            (Some(BASE64.encode(&png)), Some("image/png".to_string()))
+        );
+
+        assert_eq!(
+            file_content_data("a.zip", vec![0, 1, 2]),
+            (Some("[binary file, 3 bytes]".to_string()), None)
+        );
+
+        let mut large = b"%PDF-".to_vec();
+        large.resize(MAX_MEDIA_BYTES + 1, 0);
+        assert_eq!(
+            file_content_data("a.pdf", large),
+            (
+                Some(format!(
+                    "[binary file, {} bytes, larger than the {} byte limit]",
+                    MAX_MEDIA_BYTES + 1,
+                    MAX_MEDIA_BYTES
+                )),
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn search_index_finds_messages_newest_first() {
+        let mut index = SearchIndex::default();
+        index.add(
+            "a",
+            &Message::new("user".into(), "Parse the config file".into(), None),
+        );
+        index.add(
+            "b",
+            &Message::new("assistant".into(), "The parser is fixed".into(), None),
+        );
+        index.add("c", &Message::new("user".into(), "Unrelated".into(), None));
+
+        assert_eq!(index.lookup(&search_terms("the")), vec!["b", "a"]);
+        // Only the last term may be a prefix
+        assert_eq!(index.lookup(&search_terms("the pars")), vec!["b", "a"]);
+        assert_eq!(
+            index.lookup(&search_terms("pars the")),
+            Vec::<String>::new()
+        );
+        assert_eq!(index.lookup(&search_terms("config the")), vec!["a"]);
+        assert!(index.lookup(&[]).is_empty());
+    }
+
+    #[test]
+    fn search_index_covers_result_paths() {
+        let mut message = Message::new("assistant".into(), "Done".into(), None);
+        message.fs_results = Some(vec![FsResult::succeeded(
+            "write-file".into(),
+            "repo:src/main.rs".into(),
+            None,
+        )]);
+        let mut index = SearchIndex::default();
+        index.add("a", &message);
+        assert_eq!(index.lookup(&search_terms("main")), vec!["a"]);
+    }
+
+    #[test]
+    fn search_index_add_and_remove() {
+        let mut index = SearchIndex::default();
+        let message = Message::new("user".into(), "hello world".into(), None);
+        index.add("a", &message);
+        // Adding a message again doesn't index it twice
+        index.add("a", &message);
+        assert_eq!(index.messages.len(), 1);
+        assert_eq!(index.lookup(&search_terms("hello")), vec!["a"]);
+
+        index.remove("a");
+        assert!(index.lookup(&search_terms("hello")).is_empty());
+
+        // A removed message can be indexed again, as the newest
+        index.add("b", &message);
+        index.add("a", &message);
+        assert_eq!(index.lookup(&search_terms("hello")), vec!["a", "b"]);
+    }
+}